@@ -3,8 +3,9 @@ use clap::{App, Arg, ArgMatches, SubCommand};
 use filetime::FileTime;
 
 use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
 
-use pk2::archive;
+use pk2::{archive, Encoding};
 
 fn main() {
     let app = App::new(crate_name!())
@@ -14,17 +15,68 @@ fn main() {
         .subcommand(extract_app())
         .subcommand(repack_app())
         .subcommand(pack_app())
-        .subcommand(list_app());
+        .subcommand(list_app())
+        .subcommand(compact_app())
+        .subcommand(verify_app())
+        .subcommand(repair_app())
+        .subcommand(detect_key_app());
     let matches = app.get_matches();
     match matches.subcommand() {
         ("extract", Some(matches)) => extract(matches),
         ("repack", Some(matches)) => repack(matches),
         ("pack", Some(matches)) => pack(matches),
         ("list", Some(matches)) => list(matches),
+        ("compact", Some(matches)) => compact(matches),
+        ("verify", Some(matches)) => verify(matches),
+        ("repair", Some(matches)) => repair(matches),
+        ("detect-key", Some(matches)) => detect_key(matches),
         _ => println!("{}", matches.usage()),
     }
 }
 
+fn encoding_arg() -> Arg<'static, 'static> {
+    Arg::with_name("encoding")
+        .short("e")
+        .long("encoding")
+        .takes_value(true)
+        .default_value("euc-kr")
+        .possible_values(&[
+            "euc-kr",
+            "gbk",
+            "shift-jis",
+            "big5",
+            "windows-1254",
+            "windows-1258",
+            "utf-8",
+        ])
+        .help("Sets the encoding of entry names")
+}
+
+fn timestamp_arg() -> Arg<'static, 'static> {
+    Arg::with_name("timestamp")
+        .short("t")
+        .long("timestamp")
+        .takes_value(true)
+        .help("Sets a fixed time in seconds since the unix epoch for all entries, so that the same input always produces the same archive")
+}
+
+fn timestamp(matches: &ArgMatches<'static>) -> Option<SystemTime> {
+    matches.value_of("timestamp").map(|secs| {
+        let secs = secs
+            .parse()
+            .unwrap_or_else(|_| panic!("invalid timestamp {:?}", secs));
+        SystemTime::UNIX_EPOCH + Duration::from_secs(secs)
+    })
+}
+
+fn open_options(matches: &ArgMatches<'static>) -> archive::OpenOptions {
+    let encoding = Encoding::from_name(matches.value_of("encoding").unwrap()).unwrap();
+    let mut options = archive::OpenOptions::new();
+    options.encoding(encoding);
+    options.fixed_time(timestamp(matches));
+    options
+}
+
 fn extract_app() -> App<'static, 'static> {
     SubCommand::with_name("extract")
         .version(crate_version!())
@@ -46,6 +98,7 @@ fn extract_app() -> App<'static, 'static> {
                 .default_value("169841")
                 .help("Sets the blowfish key"),
         )
+        .arg(encoding_arg())
         .arg(
             Arg::with_name("out")
                 .short("o")
@@ -69,18 +122,23 @@ fn extract(matches: &ArgMatches<'static>) {
         .map(PathBuf::from)
         .unwrap_or_else(|| archive_path.with_extension(""));
     let write_times = matches.is_present("time");
-    let archive = archive::Pk2::open(archive_path, key)
+    let archive = open_options(matches)
+        .open_readonly(archive_path, key)
         .expect(&format!("failed to open archive at {:?}", archive_path));
     let folder = archive.open_directory("/").unwrap();
     println!("Extracting {:?} to {:?}.", archive_path, out_path);
     extract_files(folder, &out_path, write_times);
 }
 
-fn extract_files(folder: archive::fs::Directory<'_>, out_path: &Path, write_times: bool) {
+fn extract_files(
+    folder: archive::fs::Directory<'_, archive::ReadOnly>,
+    out_path: &Path,
+    write_times: bool,
+) {
     use std::io::Read;
     let _ = std::fs::create_dir(out_path);
     let mut buf = Vec::new();
-    for entry in folder.entries() {
+    for entry in folder.entries().unwrap() {
         match entry {
             archive::fs::DirEntry::File(mut file) => {
                 file.read_to_end(&mut buf).unwrap();
@@ -136,6 +194,7 @@ fn repack_app() -> App<'static, 'static> {
                 .takes_value(true)
                 .help("Sets the blowfish key for the output archive"),
         )
+        .arg(encoding_arg())
         .arg(
             Arg::with_name("out")
                 .short("o")
@@ -143,6 +202,7 @@ fn repack_app() -> App<'static, 'static> {
                 .takes_value(true)
                 .help("Sets the output path to repack to"),
         )
+        .arg(timestamp_arg())
 }
 
 fn repack(matches: &ArgMatches<'static>) {
@@ -157,31 +217,47 @@ fn repack(matches: &ArgMatches<'static>) {
         .value_of_os("out")
         .map(PathBuf::from)
         .unwrap_or_else(|| archive_path.with_extension("repack.pk2"));
-    let in_archive = pk2::archive::Pk2::open(archive_path, key)
+    let options = open_options(matches);
+    let in_archive = options
+        .open_readonly(archive_path, key)
         .expect(&format!("failed to open archive at {:?}", archive_path));
-    let mut out_archive = pk2::archive::Pk2::create_new(&out_archive_path, packkey).expect(
-        &format!("failed to create archive at {:?}", out_archive_path),
-    );
+    let mut builder = archive::Pk2Builder::with_options(&options);
+    let fixed_time = timestamp(matches);
     let folder = in_archive.open_directory("/").unwrap();
+    let times = fixed_time.map_or_else(|| folder.times(), archive::FileTimes::new);
+    builder.add_directory("/", times).unwrap();
+    repack_files(&mut builder, folder, "/".as_ref(), fixed_time);
     println!("Repacking {:?} into {:?}.", archive_path, out_archive_path);
-    repack_files(&mut out_archive, folder, "/".as_ref());
+    let metadata = in_archive
+        .metadata()
+        .expect("failed to read the metadata of the archive");
+    let mut out_archive = builder.build(&out_archive_path, packkey).expect(&format!(
+        "failed to create archive at {:?}",
+        out_archive_path
+    ));
+    out_archive
+        .set_metadata(metadata.as_ref())
+        .expect("failed to write the metadata of the archive");
 }
 
-fn repack_files(out_archive: &mut archive::Pk2, folder: archive::fs::Directory<'_>, path: &Path) {
-    use std::io::{Read, Write};
-    let mut buf = Vec::new();
-    for entry in folder.entries() {
+fn repack_files<'a>(
+    builder: &mut archive::Pk2Builder<'a>,
+    folder: archive::fs::Directory<'a, archive::ReadOnly>,
+    path: &Path,
+    fixed_time: Option<SystemTime>,
+) {
+    for entry in folder.entries().unwrap() {
         match entry {
-            archive::fs::DirEntry::File(mut file) => {
-                file.read_to_end(&mut buf).unwrap();
-                let mut out_file = out_archive.create_file(path.join(file.name())).unwrap();
-                out_file.copy_file_times(&file);
-                out_file.write_all(&buf).unwrap();
-                buf.clear();
+            archive::fs::DirEntry::File(file) => {
+                let file_path = path.join(file.name());
+                let times = fixed_time.map_or_else(|| file.times(), archive::FileTimes::new);
+                builder.add_file(file_path, file, times).unwrap();
             }
             archive::fs::DirEntry::Directory(dir) => {
                 let path = path.join(dir.name());
-                repack_files(out_archive, dir, &path);
+                let times = fixed_time.map_or_else(|| dir.times(), archive::FileTimes::new);
+                builder.add_directory(&path, times).unwrap();
+                repack_files(builder, dir, &path, fixed_time);
             }
         }
     }
@@ -208,6 +284,7 @@ fn pack_app() -> App<'static, 'static> {
                 .default_value("169841")
                 .help("Sets the blowfish key for the resulting archive"),
         )
+        .arg(encoding_arg())
         .arg(
             Arg::with_name("archive")
                 .short("a")
@@ -215,6 +292,13 @@ fn pack_app() -> App<'static, 'static> {
                 .takes_value(true)
                 .help("Sets the output path to pack into"),
         )
+        .arg(timestamp_arg())
+        .arg(
+            Arg::with_name("mtime-only")
+                .short("m")
+                .long("mtime-only")
+                .help("Only uses the modification times of the packed files, as their access times change when packing them. Use --timestamp to get identical archives from checkouts with different modification times"),
+        )
 }
 
 fn pack(matches: &ArgMatches<'static>) {
@@ -227,36 +311,81 @@ fn pack(matches: &ArgMatches<'static>) {
     if !input_path.is_dir() {
         return;
     }
-    let mut out_archive = archive::Pk2::create_new(&out_archive_path, key).expect(&format!(
+    let mut builder = archive::Pk2Builder::with_options(&open_options(matches));
+    let times = file_times(&input_path.metadata().unwrap(), matches);
+    builder.add_directory("/", times).unwrap();
+    pack_files(&mut builder, input_path, input_path, matches);
+    println!("Packing {:?} into {:?}.", input_path, out_archive_path);
+    builder.build(&out_archive_path, key).expect(&format!(
         "failed to create archive at {:?}",
         out_archive_path
     ));
-    println!("Packing {:?} into {:?}.", input_path, out_archive_path);
-    pack_files(&mut out_archive, input_path, input_path);
 }
 
-fn pack_files(out_archive: &mut archive::Pk2, dir_path: &Path, base: &Path) {
-    use std::io::{Read, Write};
-    let mut buf = Vec::new();
+fn pack_files(
+    builder: &mut archive::Pk2Builder<'_>,
+    dir_path: &Path,
+    base: &Path,
+    matches: &ArgMatches<'static>,
+) {
     for entry in std::fs::read_dir(dir_path).unwrap() {
         let entry = entry.unwrap();
         let ty = entry.file_type().unwrap();
         let path = entry.path();
+        let archive_path = Path::new("/").join(path.strip_prefix(base).unwrap());
+        let times = file_times(&entry.metadata().unwrap(), matches);
         if ty.is_dir() {
-            pack_files(out_archive, &path, base);
+            builder.add_directory(archive_path, times).unwrap();
+            pack_files(builder, &path, base, matches);
         } else if ty.is_file() {
-            let mut file = std::fs::File::open(&path).unwrap();
-            file.read_to_end(&mut buf).unwrap();
-            out_archive
-                .create_file(Path::new("/").join(path.strip_prefix(base).unwrap()))
-                .unwrap()
-                .write_all(&buf)
+            builder
+                .add_file(archive_path, LazyFile::new(path), times)
                 .unwrap();
-            buf.clear();
         }
     }
 }
 
+fn file_times(metadata: &std::fs::Metadata, matches: &ArgMatches<'static>) -> archive::FileTimes {
+    if let Some(time) = timestamp(matches) {
+        return archive::FileTimes::new(time);
+    }
+    let modified = metadata.modified().unwrap_or_else(|_| SystemTime::now());
+    let mut times = archive::FileTimes::new(modified);
+    if matches.is_present("mtime-only") {
+        return times;
+    }
+    if let Ok(accessed) = metadata.accessed() {
+        times.set_access_time(accessed);
+    }
+    if let Ok(created) = metadata.created() {
+        times.set_create_time(created);
+    }
+    times
+}
+
+/// A file that only gets opened once it is read from, so that packing doesn't
+/// keep every file of the packed directory open at once.
+struct LazyFile {
+    path: PathBuf,
+    file: Option<std::fs::File>,
+}
+
+impl LazyFile {
+    fn new(path: PathBuf) -> Self {
+        LazyFile { path, file: None }
+    }
+}
+
+impl std::io::Read for LazyFile {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let file = match &mut self.file {
+            Some(file) => file,
+            None => self.file.insert(std::fs::File::open(&self.path)?),
+        };
+        file.read(buf)
+    }
+}
+
 fn list_app() -> App<'static, 'static> {
     SubCommand::with_name("list")
         .version(crate_version!())
@@ -278,6 +407,7 @@ fn list_app() -> App<'static, 'static> {
                 .default_value("169841")
                 .help("Sets the blowfish key"),
         )
+        .arg(encoding_arg())
         .arg(
             Arg::with_name("time")
                 .short("t")
@@ -289,15 +419,16 @@ fn list_app() -> App<'static, 'static> {
 fn list(matches: &ArgMatches<'static>) {
     let key = matches.value_of("key").unwrap().as_bytes();
     let archive_path = matches.value_of_os("archive").map(PathBuf::from).unwrap();
-    let archive = archive::Pk2::open(&archive_path, key)
+    let archive = open_options(matches)
+        .open_readonly(&archive_path, key)
         .expect(&format!("failed to open archive at {:?}", archive_path));
     let folder = archive.open_directory("/").unwrap();
     list_files(folder, "/".as_ref(), 1);
 }
 
-fn list_files(folder: archive::fs::Directory, path: &Path, ident_level: usize) {
+fn list_files(folder: archive::fs::Directory<archive::ReadOnly>, path: &Path, ident_level: usize) {
     println!("{}", path.display());
-    for entry in folder.entries() {
+    for entry in folder.entries().unwrap() {
         match entry {
             archive::fs::DirEntry::File(file) => {
                 println!("{}{}", " ".repeat(ident_level), file.name());
@@ -310,3 +441,214 @@ fn list_files(folder: archive::fs::Directory, path: &Path, ident_level: usize) {
         }
     }
 }
+
+fn compact_app() -> App<'static, 'static> {
+    SubCommand::with_name("compact")
+        .version(crate_version!())
+        .author(crate_authors!())
+        .about(crate_description!())
+        .arg(
+            Arg::with_name("archive")
+                .short("a")
+                .long("archive")
+                .required(true)
+                .takes_value(true)
+                .help("Sets the archive to compact"),
+        )
+        .arg(
+            Arg::with_name("key")
+                .short("k")
+                .long("key")
+                .takes_value(true)
+                .default_value("169841")
+                .help("Sets the blowfish key"),
+        )
+        .arg(encoding_arg())
+}
+
+fn compact(matches: &ArgMatches<'static>) {
+    let key = matches.value_of("key").unwrap().as_bytes();
+    let archive_path = matches.value_of_os("archive").map(PathBuf::from).unwrap();
+    let mut archive = open_options(matches)
+        .open(&archive_path, key)
+        .unwrap_or_else(|e| panic!("failed to open archive at {:?}: {}", archive_path, e));
+    let old_len = std::fs::metadata(&archive_path).map(|m| m.len()).unwrap();
+    println!("Compacting {:?}.", archive_path);
+    let new_len = archive.compact().unwrap();
+    println!(
+        "Reduced archive size from {} to {} bytes.",
+        old_len, new_len
+    );
+}
+
+fn verify_app() -> App<'static, 'static> {
+    SubCommand::with_name("verify")
+        .version(crate_version!())
+        .author(crate_authors!())
+        .about(crate_description!())
+        .arg(
+            Arg::with_name("archive")
+                .short("a")
+                .long("archive")
+                .required(true)
+                .takes_value(true)
+                .help("Sets the archive to verify"),
+        )
+        .arg(
+            Arg::with_name("key")
+                .short("k")
+                .long("key")
+                .takes_value(true)
+                .default_value("169841")
+                .help("Sets the blowfish key"),
+        )
+        .arg(encoding_arg())
+}
+
+fn verify(matches: &ArgMatches<'static>) {
+    let key = matches.value_of("key").unwrap().as_bytes();
+    let archive_path = matches.value_of_os("archive").map(PathBuf::from).unwrap();
+    // opened lazily so that unreadable chains get reported instead of failing to open
+    let archive = open_options(matches)
+        .lazy(true)
+        .open_readonly(&archive_path, key)
+        .unwrap_or_else(|e| panic!("failed to open archive at {:?}: {}", archive_path, e));
+    let report = archive.check().unwrap();
+    for problem in &report.problems {
+        println!("{}", problem);
+    }
+    if report.is_ok() {
+        println!("No problems found in {:?}.", archive_path);
+    } else {
+        println!(
+            "Found {} problems in {:?}.",
+            report.problems.len(),
+            archive_path
+        );
+        std::process::exit(1);
+    }
+}
+
+fn repair_app() -> App<'static, 'static> {
+    SubCommand::with_name("repair")
+        .version(crate_version!())
+        .author(crate_authors!())
+        .about(crate_description!())
+        .arg(
+            Arg::with_name("archive")
+                .short("a")
+                .long("archive")
+                .required(true)
+                .takes_value(true)
+                .help("Sets the archive to repair"),
+        )
+        .arg(
+            Arg::with_name("key")
+                .short("k")
+                .long("key")
+                .takes_value(true)
+                .default_value("169841")
+                .help("Sets the blowfish key"),
+        )
+        .arg(encoding_arg())
+        .arg(
+            Arg::with_name("out")
+                .short("o")
+                .long("out")
+                .takes_value(true)
+                .help("Writes the repaired archive to this path instead of repairing it in place"),
+        )
+}
+
+fn repair(matches: &ArgMatches<'static>) {
+    let key = matches.value_of("key").unwrap().as_bytes();
+    let mut archive_path = matches.value_of_os("archive").map(PathBuf::from).unwrap();
+    if let Some(out_path) = matches.value_of_os("out").map(PathBuf::from) {
+        std::fs::copy(&archive_path, &out_path).unwrap_or_else(|e| {
+            panic!(
+                "failed to copy archive {:?} to {:?}: {}",
+                archive_path, out_path, e
+            )
+        });
+        archive_path = out_path;
+    }
+    let mut archive = open_options(matches)
+        .tolerant(true)
+        .open(&archive_path, key)
+        .unwrap_or_else(|e| panic!("failed to open archive at {:?}: {}", archive_path, e));
+    println!("Repairing {:?}.", archive_path);
+    let log = archive.repair().unwrap();
+    for repair in &log.repairs {
+        println!("{}", repair);
+    }
+    if log.is_empty() {
+        println!("Nothing to repair.");
+    } else {
+        println!("Made {} repairs.", log.repairs.len());
+    }
+}
+
+fn detect_key_app() -> App<'static, 'static> {
+    SubCommand::with_name("detect-key")
+        .version(crate_version!())
+        .author(crate_authors!())
+        .about(crate_description!())
+        .arg(
+            Arg::with_name("archive")
+                .short("a")
+                .long("archive")
+                .required(true)
+                .takes_value(true)
+                .help("Sets the archive to detect the key of"),
+        )
+        .arg(
+            Arg::with_name("wordlist")
+                .short("w")
+                .long("wordlist")
+                .takes_value(true)
+                .help("Sets a file of additional keys to try, one per line"),
+        )
+}
+
+fn detect_key(matches: &ArgMatches<'static>) {
+    let archive_path = matches.value_of_os("archive").map(PathBuf::from).unwrap();
+    let wordlist = match matches.value_of_os("wordlist").map(PathBuf::from) {
+        Some(path) => std::fs::read(&path)
+            .unwrap_or_else(|e| panic!("failed to read wordlist at {:?}: {}", path, e)),
+        None => Vec::new(),
+    };
+    let keys = pk2::constants::PK2_KNOWN_KEYS
+        .iter()
+        .map(|&(_, key)| key)
+        .chain(
+            wordlist
+                .split(|&b| b == b'\n')
+                .map(|line| line.strip_suffix(b"\r").unwrap_or(line))
+                .filter(|line| !line.is_empty()),
+        );
+    let file = std::fs::File::open(&archive_path)
+        .unwrap_or_else(|e| panic!("failed to open archive at {:?}: {}", archive_path, e));
+    match archive::Pk2::detect_key(file, keys, &archive::OpenOptions::new()).unwrap() {
+        Some([]) => println!("{:?} is not encrypted.", archive_path),
+        Some(key) => {
+            let regions = pk2::constants::PK2_KNOWN_KEYS
+                .iter()
+                .find(|&&(_, known)| known == key)
+                .map_or(&[][..], |&(regions, _)| regions);
+            println!(
+                "{:?} is encrypted with the key {:?}{}.",
+                archive_path,
+                String::from_utf8_lossy(key),
+                if regions.is_empty() {
+                    String::new()
+                } else {
+                    format!(" ({})", regions.join(", "))
+                }
+            );
+        }
+        None => {
+            println!("None of the keys matched {:?}.", archive_path);
+            std::process::exit(1);
+        }
+    }
+}
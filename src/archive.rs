@@ -1,46 +1,212 @@
-use std::cell::RefCell;
+use std::collections::HashSet;
+use std::num::NonZeroU64;
 use std::path::{Component, Path};
+use std::sync::{Mutex, MutexGuard, PoisonError};
 use std::{fs as stdfs, io};
 
 use crate::constants::{
-    PK2_CHECKSUM, PK2_CURRENT_DIR_IDENT, PK2_PARENT_DIR_IDENT, PK2_ROOT_BLOCK,
+    PK2_CHECKSUM, PK2_CURRENT_DIR_IDENT, PK2_FILE_BLOCK_SIZE, PK2_PARENT_DIR_IDENT, PK2_ROOT_BLOCK,
     PK2_ROOT_BLOCK_VIRTUAL,
 };
 use crate::error::{Error, Pk2Result};
 use crate::io::RawIo;
-use crate::Blowfish;
+use crate::{Blowfish, Encoding, Key, FILETIME};
 
+mod builder;
+pub use self::builder::{FileTimes, Pk2Builder};
+pub mod check;
 pub mod fs;
-use self::fs::{DirEntry, Directory, File, FileMut};
+mod glob;
+pub mod metadata;
+pub mod repair;
+use self::fs::{DirEntry, Directory, File, FileMut, FileStream, Glob, Walk};
+
+mod journal;
+use self::journal::{Journal, JournaledFile};
+
+#[cfg(feature = "mmap")]
+pub mod mmap;
+
+mod options;
+pub use self::options::OpenOptions;
 
 use crate::raw::block_chain::{PackBlock, PackBlockChain};
 use crate::raw::block_manager::BlockManager;
 use crate::raw::entry::*;
+use crate::raw::free_list::FreeList;
 use crate::raw::header::PackHeader;
-use crate::raw::ChainIndex;
+use crate::raw::{BlockOffset, ChainIndex};
 
 pub struct Pk2<B = stdfs::File> {
-    file: RefCell<B>,
+    file: LockedFile<B>,
     blowfish: Option<Blowfish>,
+    encoding: Encoding,
     block_manager: BlockManager,
+    free_list: FreeList,
+    // used instead of the current time for created or modified entries
+    fixed_time: Option<FILETIME>,
+}
+
+/// The underlying file of an archive. Access to it is guarded by a lock so
+/// that an archive can be shared between threads for reading. Writes go
+/// through the journal if the archive has one.
+struct LockedFile<B> {
+    file: Mutex<B>,
+    journal: Option<Journal>,
+    // the contents of memory mapped archives, readable without the lock
+    #[cfg(feature = "mmap")]
+    map: Option<std::sync::Arc<memmap2::Mmap>>,
+}
+
+impl<B> LockedFile<B> {
+    fn new(file: B) -> Self {
+        LockedFile {
+            file: Mutex::new(file),
+            journal: None,
+            #[cfg(feature = "mmap")]
+            map: None,
+        }
+    }
+
+    /// Returns the contents of the archive if it is memory mapped.
+    #[cfg(feature = "mmap")]
+    #[inline]
+    fn mapped(&self) -> Option<&[u8]> {
+        self.map.as_deref().map(|map| &map[..])
+    }
+
+    /// Locks the file for the duration of a seek and read. The lock only
+    /// guards the cursor position which is always reset before use, so a
+    /// poisoned lock is safe to reuse.
+    #[inline]
+    fn lock(&self) -> MutexGuard<'_, B> {
+        self.file.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    #[inline]
+    fn get_mut(&mut self) -> JournaledFile<'_, B> {
+        JournaledFile::new(
+            self.file.get_mut().unwrap_or_else(PoisonError::into_inner),
+            self.journal.as_mut(),
+        )
+    }
+
+    /// Starts an operation that modifies the archive. Fails if the previous
+    /// one failed midway without committing, as committing further writes
+    /// would make its partial changes permanent. Reopening the archive rolls
+    /// them back.
+    fn begin(&self) -> io::Result<()> {
+        match &self.journal {
+            Some(journal) if journal.in_transaction() => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "a previous operation failed midway and has to be rolled back by reopening the archive",
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Ends the current transaction, making all writes since the last commit
+    /// permanent. Every operation that modifies the archive has to call this
+    /// once it is done.
+    fn commit(&mut self) -> io::Result<()>
+    where
+        B: io::Write,
+    {
+        match &mut self.journal {
+            Some(journal) => {
+                journal.commit(self.file.get_mut().unwrap_or_else(PoisonError::into_inner))
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+/// A backend whose length can be changed. This is required for shrinking an
+/// archive after compacting it.
+pub trait Truncate {
+    fn truncate(&mut self, len: u64) -> io::Result<()>;
+}
+
+impl Truncate for stdfs::File {
+    fn truncate(&mut self, len: u64) -> io::Result<()> {
+        self.set_len(len)
+    }
+}
+
+impl Truncate for io::Cursor<Vec<u8>> {
+    fn truncate(&mut self, len: u64) -> io::Result<()> {
+        self.get_mut().truncate(len as usize);
+        Ok(())
+    }
+}
+
+impl<T: Truncate + ?Sized> Truncate for &mut T {
+    fn truncate(&mut self, len: u64) -> io::Result<()> {
+        (**self).truncate(len)
+    }
+}
+
+/// A backend that only allows reading and seeking the wrapped one. Archives
+/// opened in it can't be modified, as every modifying operation requires
+/// the backend to implement [`Write`](io::Write).
+#[derive(Debug)]
+pub struct ReadOnly<B = stdfs::File>(B);
+
+impl<B> ReadOnly<B> {
+    pub fn new(inner: B) -> Self {
+        ReadOnly(inner)
+    }
+
+    pub fn get_ref(&self) -> &B {
+        &self.0
+    }
+
+    pub fn into_inner(self) -> B {
+        self.0
+    }
+}
+
+impl<B: io::Read> io::Read for ReadOnly<B> {
+    #[inline]
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl<B: io::Seek> io::Seek for ReadOnly<B> {
+    #[inline]
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        self.0.seek(pos)
+    }
+}
+
+impl Pk2<ReadOnly> {
+    /// Opens the archive at the given path for reading only. Unlike
+    /// [`Pk2::open`] this doesn't require write access to the file.
+    pub fn open_readonly<P: AsRef<Path>, K: AsRef<[u8]>>(path: P, key: K) -> Pk2Result<Self> {
+        OpenOptions::new().open_readonly(path, key)
+    }
 }
 
 impl Pk2<stdfs::File> {
     pub fn create_new<P: AsRef<Path>, K: AsRef<[u8]>>(path: P, key: K) -> Pk2Result<Self> {
-        let file = stdfs::OpenOptions::new()
-            .create_new(true)
-            .write(true)
-            .read(true)
-            .open(path.as_ref())?;
-        Self::_create_impl(file, key)
+        OpenOptions::new().create_new(path, key)
     }
 
+    /// Opens the archive at the given path. If a previous modification of the
+    /// archive got interrupted while journaling, it is rolled back first.
     pub fn open<P: AsRef<Path>, K: AsRef<[u8]>>(path: P, key: K) -> Pk2Result<Self> {
-        let file = stdfs::OpenOptions::new()
-            .write(true)
-            .read(true)
-            .open(path)?;
-        Self::_open_in_impl(file, key)
+        OpenOptions::new().open(path, key)
+    }
+
+    /// Opens the archive at the given path with the first key of
+    /// [`PK2_KNOWN_KEYS`] it is encrypted with, returning the key alongside
+    /// the archive. Unencrypted archives yield an empty key. Fails with
+    /// [`Error::InvalidKey`] if no known key matches.
+    ///
+    /// [`PK2_KNOWN_KEYS`]: crate::constants::PK2_KNOWN_KEYS
+    pub fn open_detect_key<P: AsRef<Path>>(path: P) -> Pk2Result<(Self, &'static [u8])> {
+        OpenOptions::new().open_detect_key(path)
     }
 }
 
@@ -48,29 +214,88 @@ impl<B> Pk2<B>
 where
     B: io::Read + io::Seek,
 {
-    pub fn open_in<K: AsRef<[u8]>>(mut file: B, key: K) -> Pk2Result<Self> {
+    pub fn open_in<K: AsRef<[u8]>>(file: B, key: K) -> Pk2Result<Self> {
+        OpenOptions::new().open_in(file, key)
+    }
+
+    /// Returns the first of `keys` the archive in `file` is encrypted with, or
+    /// `None` if none of them is. Unencrypted archives yield an empty key.
+    /// The header of the archive is validated according to `options`.
+    ///
+    /// Besides checking the key against the header, the root block gets
+    /// decrypted and parsed to rule out false positives, as the header only
+    /// stores a few bytes of its checksum.
+    pub fn detect_key<'k, I>(
+        mut file: B,
+        keys: I,
+        options: &OpenOptions,
+    ) -> Pk2Result<Option<&'k [u8]>>
+    where
+        I: IntoIterator<Item = &'k [u8]>,
+    {
+        options.validate()?;
         file.seek(io::SeekFrom::Start(0))?;
-        Self::_open_in_impl(file, key)
+        let header = PackHeader::from_reader(&mut file)?;
+        options.validate_header(&header)?;
+        if !header.encrypted {
+            return Ok(Some(&[]));
+        }
+        for key in keys {
+            let bf = match Blowfish::new(key) {
+                Ok(bf) => bf,
+                Err(_) => continue,
+            };
+            if header.verify_key(&bf).is_err() {
+                continue;
+            }
+            match crate::io::read_block_at(
+                Some(&bf),
+                options.encoding,
+                &mut file,
+                PK2_ROOT_BLOCK.into(),
+            ) {
+                Ok(_) => return Ok(Some(key)),
+                Err(Error::CorruptedFile) => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(None)
     }
 
-    fn _open_in_impl<K: AsRef<[u8]>>(mut file: B, key: K) -> Pk2Result<Self> {
+    fn _open_in_impl(mut file: B, key: Key<'_>, options: &OpenOptions) -> Pk2Result<Self> {
         let header = PackHeader::from_reader(&mut file)?;
-        header.validate_sig()?;
+        options.validate_header(&header)?;
         let blowfish = if header.encrypted {
-            let bf = Blowfish::new(key.as_ref())?;
-            let mut checksum = *PK2_CHECKSUM;
-            let _ = bf.encrypt(&mut checksum);
-            header.verify(checksum)?;
+            let bf = key.blowfish()?.ok_or(Error::InvalidKey)?;
+            header.verify_key(&bf)?;
             Some(bf)
         } else {
             None
         };
-        let block_manager = BlockManager::new(blowfish.as_ref(), &mut file)?;
+        let (block_manager, free_list) = if options.tolerant {
+            let block_manager =
+                BlockManager::new_tolerant(blowfish.as_ref(), options.encoding, &mut file)?;
+            let free_list = Self::compute_free_list(&block_manager, &mut file)?;
+            (block_manager, free_list)
+        } else if options.lazy {
+            // the used regions aren't known yet, so writes append to the file until everything
+            // gets loaded
+            let block_manager =
+                BlockManager::new_lazy(blowfish.as_ref(), options.encoding, &mut file)?;
+            (block_manager, FreeList::new())
+        } else {
+            let block_manager = BlockManager::new(blowfish.as_ref(), options.encoding, &mut file)?;
+            let free_list = Self::compute_free_list(&block_manager, &mut file)?;
+            (block_manager, free_list)
+        };
 
         Ok(Pk2 {
-            file: RefCell::new(file),
+            file: LockedFile::new(file),
             blowfish,
+            encoding: options.encoding,
             block_manager,
+            free_list,
+            fixed_time: options.fixed_time,
         })
     }
 }
@@ -79,34 +304,133 @@ impl<B> Pk2<B>
 where
     B: io::Read + io::Write + io::Seek,
 {
-    pub fn create_new_in<K: AsRef<[u8]>>(mut file: B, key: K) -> Pk2Result<Self> {
-        file.seek(io::SeekFrom::Start(0))?;
-        Self::_create_impl(file, key)
+    pub fn create_new_in<K: AsRef<[u8]>>(file: B, key: K) -> Pk2Result<Self> {
+        OpenOptions::new().create_new_in(file, key)
     }
 
-    fn _create_impl<K: AsRef<[u8]>>(file: B, key: K) -> Pk2Result<Self> {
-        let (header, mut file, blowfish) = if key.as_ref().is_empty() {
-            (PackHeader::default(), file, None)
-        } else {
-            let bf = Blowfish::new(key.as_ref())?;
-            (PackHeader::new_encrypted(&bf), file, Some(bf))
+    fn _create_impl(file: B, key: Key<'_>, options: &OpenOptions) -> Pk2Result<Self> {
+        let (mut header, mut file, blowfish) = match key.blowfish()? {
+            None => (PackHeader::default(), file, None),
+            Some(bf) => (PackHeader::new_encrypted(&bf), file, Some(bf)),
         };
+        header.signature = options.signature;
+        header.version = options.version;
 
         header.to_writer(&mut file)?;
         let mut block = PackBlock::default();
         block[0] = PackEntry::new_directory(PK2_CURRENT_DIR_IDENT, PK2_ROOT_BLOCK, None);
-        crate::io::write_block(blowfish.as_ref(), &mut file, PK2_ROOT_BLOCK.into(), &block)?;
+        options.default_times().apply(&mut block[0]);
+        crate::io::write_block(
+            blowfish.as_ref(),
+            options.encoding,
+            &mut file,
+            PK2_ROOT_BLOCK.into(),
+            &block,
+        )?;
 
-        let block_manager = BlockManager::new(blowfish.as_ref(), &mut file)?;
+        let block_manager = BlockManager::new(blowfish.as_ref(), options.encoding, &mut file)?;
         Ok(Pk2 {
-            file: RefCell::new(file),
+            file: LockedFile::new(file),
             blowfish,
+            encoding: options.encoding,
             block_manager,
+            free_list: FreeList::new(),
+            fixed_time: options.fixed_time,
         })
     }
 }
 
+impl<B> Pk2<B>
+where
+    B: io::Read + io::Seek,
+{
+    /// Returns the chain at the given index, loading it from the archive
+    /// first if the index is loaded lazily.
+    fn load_chain(&self, chain: ChainIndex) -> Pk2Result<&PackBlockChain> {
+        match self.block_manager.get(chain) {
+            Some(chain) => Ok(chain),
+            None => self.block_manager.load(
+                self.blowfish.as_ref(),
+                self.encoding,
+                &mut *self.file.lock(),
+                chain,
+            ),
+        }
+    }
+
+    /// Makes sure that every chain along the path starting at `chain` is
+    /// loaded, so that the path can be resolved through the [`BlockManager`].
+    /// Components that can't be resolved are left for the resolution to
+    /// report.
+    fn load_path(&self, mut chain: ChainIndex, path: &Path) -> Pk2Result<()> {
+        if !self.block_manager.is_lazy() {
+            return Ok(());
+        }
+        for component in path.components() {
+            let name = match component.as_os_str().to_str() {
+                Some(name) => name,
+                None => return Ok(()),
+            };
+            match self.load_chain(chain)?.find_block_chain_index_of(name) {
+                Ok(next) => chain = next,
+                Err(_) => return Ok(()),
+            }
+        }
+        self.load_chain(chain).map(drop)
+    }
+
+    /// Loads all chains of a lazily loaded archive. This is required before
+    /// operations that need to know the whole index, after which the archive
+    /// is no longer lazy.
+    fn load_all(&mut self) -> Pk2Result<()> {
+        if self.block_manager.is_lazy() {
+            let mut file = self.file.get_mut();
+            self.block_manager
+                .load_all(self.blowfish.as_ref(), self.encoding, &mut file)?;
+            self.free_list = Self::compute_free_list(&self.block_manager, &mut file)?;
+        }
+        Ok(())
+    }
+
+    fn compute_free_list<F: io::Seek>(
+        block_manager: &BlockManager,
+        file: F,
+    ) -> Pk2Result<FreeList> {
+        let header_region = (0, PK2_ROOT_BLOCK.0);
+        Ok(FreeList::from_used_regions(
+            block_manager.used_regions().chain(Some(header_region)),
+            crate::io::file_len(file)?,
+        ))
+    }
+
+    fn root_resolve_path_to_entry_and_parent<P: AsRef<Path>>(
+        &self,
+        path: P,
+    ) -> Pk2Result<(ChainIndex, usize, &PackEntry)> {
+        let path = check_root(path.as_ref())?;
+        self.load_path(PK2_ROOT_BLOCK, path)?;
+        self.block_manager
+            .resolve_path_to_entry_and_parent(PK2_ROOT_BLOCK, path)
+    }
+}
+
 impl<B> Pk2<B> {
+    /// The encoding entry names of this archive are stored in.
+    pub fn encoding(&self) -> Encoding {
+        self.encoding
+    }
+
+    /// The time created or modified entries get stamped with, either the
+    /// fixed time of the archive or the current time.
+    fn now(&self) -> FILETIME {
+        self.fixed_time.unwrap_or_else(FILETIME::now)
+    }
+
+    fn now_times(&self) -> FileTimes {
+        FileTimes::from_filetime(self.now())
+    }
+
+    /// Returns the chain if it has been loaded already.
     #[inline(always)]
     fn get_chain(&self, chain: ChainIndex) -> Option<&PackBlockChain> {
         self.block_manager.get(chain)
@@ -128,14 +452,6 @@ impl<B> Pk2<B> {
             .and_then(|chain| chain.get_mut(entry))
     }
 
-    fn root_resolve_path_to_entry_and_parent<P: AsRef<Path>>(
-        &self,
-        path: P,
-    ) -> Pk2Result<(ChainIndex, usize, &PackEntry)> {
-        self.block_manager
-            .resolve_path_to_entry_and_parent(PK2_ROOT_BLOCK, check_root(path.as_ref())?)
-    }
-
     pub(self) fn is_file(entry: &PackEntry) -> Pk2Result<()> {
         match entry.is_file() {
             true => Ok(()),
@@ -151,7 +467,10 @@ impl<B> Pk2<B> {
     }
 }
 
-impl<B> Pk2<B> {
+impl<B> Pk2<B>
+where
+    B: io::Read + io::Seek,
+{
     pub fn open_file<P: AsRef<Path>>(&self, path: P) -> Pk2Result<File<B>> {
         let (chain, entry_idx, entry) = self.root_resolve_path_to_entry_and_parent(path)?;
         Self::is_file(entry)?;
@@ -160,6 +479,7 @@ impl<B> Pk2<B> {
 
     pub fn open_directory<P: AsRef<Path>>(&self, path: P) -> Pk2Result<Directory<B>> {
         let path = check_root(path.as_ref())?;
+        self.load_path(PK2_ROOT_BLOCK, path)?;
         let (chain, entry_idx) = match self
             .block_manager
             .resolve_path_to_entry_and_parent(PK2_ROOT_BLOCK, path)
@@ -175,40 +495,38 @@ impl<B> Pk2<B> {
         Ok(Directory::new(self, chain, entry_idx))
     }
 
+    /// Returns an iterator over all files and directories below `base`,
+    /// including the contents of its subdirectories. The yielded paths are
+    /// `base` joined with the path of the entry relative to it.
+    pub fn walk<P: AsRef<Path>>(&self, base: P) -> Pk2Result<Walk<'_, B>> {
+        let base = base.as_ref();
+        let dir = self.open_directory(base)?;
+        Walk::new(base.to_path_buf(), dir)
+    }
+
+    /// Returns an iterator over all files and directories whose paths match
+    /// the given absolute glob pattern, like `/prim/**/*.ddj`. Components may
+    /// contain the wildcards `*`, `?` and `[...]`, a `**` component matches
+    /// any number of directories. Matching ignores ASCII case. Directories
+    /// that can't contain any matches are not traversed.
+    pub fn glob(&self, pattern: &str) -> Pk2Result<Glob<'_, B>> {
+        let pattern = pattern.strip_prefix('/').ok_or(Error::InvalidPath)?;
+        let root = self.open_directory("/")?;
+        Glob::new(glob::Pattern::new(pattern)?, "/".into(), &root)
+    }
+
     /// Invokes cb on every file in the sub directories of `base`, including
     /// files inside of its subdirectories. Cb gets invoked with its
     /// relative path to `base` and the file object.
-    // Todo, replace this with a file_paths iterator once generators are stable
     pub fn for_each_file(
         &self,
         base: impl AsRef<Path>,
         mut cb: impl FnMut(&Path, File<B>) -> Pk2Result<()>,
     ) -> Pk2Result<()> {
-        let mut path = std::path::PathBuf::new();
-        let mut stack = vec![self.open_directory(base)?];
-        let mut first_iteration = true;
-        while let Some(dir) = stack.pop() {
-            if !first_iteration {
-                path.push(dir.name());
-            } else {
-                first_iteration = false;
-            };
-            let mut files_only = true;
-            for entry in dir.entries() {
-                match entry {
-                    DirEntry::Directory(dir) => {
-                        stack.push(dir);
-                        files_only = false;
-                    }
-                    DirEntry::File(file) => {
-                        path.push(file.name());
-                        cb(&path, file)?;
-                        path.pop();
-                    }
-                }
-            }
-            if files_only {
-                path.pop();
+        let base = base.as_ref();
+        for entry in self.walk(base)? {
+            if let (path, DirEntry::File(file)) = entry? {
+                cb(path.strip_prefix(base).unwrap_or(&path), file)?;
             }
         }
         Ok(())
@@ -225,57 +543,404 @@ where
         Ok(FileMut::new(self, chain, entry_idx))
     }
 
-    /// Currently only replaces the entry with an empty one making the data
-    /// inaccessible by normal means
+    /// Replaces the entry with an empty one and marks the data region of the
+    /// file as free so that it can be reused by subsequent writes.
     pub fn delete_file<P: AsRef<Path>>(&mut self, path: P) -> Pk2Result<()> {
+        self.file.begin()?;
+        let path = check_root(path.as_ref())?;
+        self.load_path(PK2_ROOT_BLOCK, path)?;
         let (chain_index, entry_idx, entry) = self
             .block_manager
-            .resolve_path_to_entry_and_parent_mut(PK2_ROOT_BLOCK, check_root(path.as_ref())?)?;
-        Self::is_file(entry)?;
-        entry.clear();
+            .resolve_path_to_entry_and_parent(PK2_ROOT_BLOCK, path)?;
+        let (pos_data, size) = match entry.as_file() {
+            Some(file) => (file.pos_data(), file.size()),
+            None => return Err(Error::ExpectedFile),
+        };
+        self.get_chain_mut(chain_index)
+            .unwrap()
+            .clear_entry(entry_idx);
+        self.free_list.free(pos_data, size as u64);
+
+        crate::io::write_chain_entry(
+            self.blowfish.as_ref(),
+            self.encoding,
+            self.file.get_mut(),
+            self.block_manager.get(chain_index).unwrap(),
+            entry_idx,
+        )?;
+        self.file.commit()?;
+        Ok(())
+    }
+
+    /// Deletes the empty directory at the given path, marking the blocks of its
+    /// block chain as free.
+    pub fn delete_directory<P: AsRef<Path>>(&mut self, path: P) -> Pk2Result<()> {
+        self.delete_directory_impl(path.as_ref(), false)
+    }
+
+    /// Deletes the directory at the given path including all of its contents,
+    /// marking the blocks of all contained block chains and the data regions
+    /// of all contained files as free.
+    pub fn delete_directory_all<P: AsRef<Path>>(&mut self, path: P) -> Pk2Result<()> {
+        self.delete_directory_impl(path.as_ref(), true)
+    }
+
+    fn delete_directory_impl(&mut self, path: &Path, recursive: bool) -> Pk2Result<()> {
+        self.file.begin()?;
+        let path = check_root(path)?;
+        if recursive {
+            // every chain of the subtree is required to free its blocks and files
+            self.load_all()?;
+        } else {
+            self.load_path(PK2_ROOT_BLOCK, path)?;
+        }
+        let (chain_index, entry_idx, entry) = self
+            .block_manager
+            .resolve_path_to_entry_and_parent(PK2_ROOT_BLOCK, path)?;
+        let children = match entry.as_directory() {
+            Some(dir) if dir.is_normal_link() => dir.children_position(),
+            // `.` and `..` links can't be deleted
+            Some(_) => return Err(Error::InvalidPath),
+            None => return Err(Error::ExpectedDirectory),
+        };
+        if !recursive {
+            let is_empty = self
+                .load_chain(children)?
+                .entries()
+                .all(|entry| match entry {
+                    PackEntry::Directory(dir) => !dir.is_normal_link(),
+                    PackEntry::File(_) => false,
+                    PackEntry::Empty(_) => true,
+                });
+            if !is_empty {
+                return Err(Error::DirectoryNotEmpty);
+            }
+        }
+        // collect the chains of the subtree up front, a chain that is reachable
+        // twice or that belongs to an ancestor would otherwise get freed while
+        // still being in use
+        let mut visited = HashSet::new();
+        for ancestor in path.ancestors().skip(1) {
+            visited.insert(
+                self.block_manager
+                    .resolve_path_to_block_chain_index_at(PK2_ROOT_BLOCK, ancestor)?,
+            );
+        }
+        let mut subtree = Vec::new();
+        let mut pending = vec![children];
+        while let Some(chain_index) = pending.pop() {
+            if !visited.insert(chain_index) {
+                return Err(Error::CorruptedFile);
+            }
+            let chain = self
+                .get_chain(chain_index)
+                .ok_or(Error::InvalidChainIndex)?;
+            pending.extend(
+                chain
+                    .entries()
+                    .filter_map(PackEntry::as_directory)
+                    .filter(|dir| dir.is_normal_link())
+                    .map(DirectoryEntry::children_position),
+            );
+            subtree.push(chain_index);
+        }
 
+        self.get_chain_mut(chain_index)
+            .unwrap()
+            .clear_entry(entry_idx);
         crate::io::write_chain_entry(
             self.blowfish.as_ref(),
-            &mut *self.file.borrow_mut(),
-            self.get_chain(chain_index).unwrap(),
+            self.encoding,
+            self.file.get_mut(),
+            self.block_manager.get(chain_index).unwrap(),
             entry_idx,
         )?;
+        for chain_index in subtree {
+            let chain = self.block_manager.remove(chain_index).unwrap();
+            for file in chain.entries().filter_map(PackEntry::as_file) {
+                self.free_list.free(file.pos_data(), file.size() as u64);
+            }
+            for BlockOffset(offset) in chain.block_offsets() {
+                self.free_list.free(offset, PK2_FILE_BLOCK_SIZE as u64);
+            }
+        }
+        self.file.commit()?;
+        Ok(())
+    }
+
+    /// Moves the file or directory at `from` to `to` without touching its
+    /// data. Directories leading up to `to` that don't exist yet will be
+    /// created.
+    pub fn rename<P: AsRef<Path>, Q: AsRef<Path>>(&mut self, from: P, to: Q) -> Pk2Result<()> {
+        self.file.begin()?;
+        let from = check_root(from.as_ref())?;
+        let to = check_root(to.as_ref())?;
+        let new_name = to
+            .file_name()
+            .ok_or(Error::InvalidPath)?
+            .to_str()
+            .ok_or(Error::NonUnicodePath)?;
+        self.load_path(PK2_ROOT_BLOCK, from)?;
+        self.load_path(PK2_ROOT_BLOCK, to)?;
+        let (src_chain, src_idx, entry) = self
+            .block_manager
+            .resolve_path_to_entry_and_parent(PK2_ROOT_BLOCK, from)?;
+        let entry = entry.clone();
+        let moved_chain = match &entry {
+            PackEntry::Directory(dir) if dir.is_normal_link() => Some(dir.children_position()),
+            PackEntry::Directory(_) | PackEntry::Empty(_) => return Err(Error::InvalidPath),
+            PackEntry::File(_) => None,
+        };
+        // renaming an entry to itself, possibly changing the case of its name
+        let dst = self
+            .block_manager
+            .resolve_path_to_entry_and_parent(PK2_ROOT_BLOCK, to);
+        if let Ok((dst_chain, dst_idx, _)) = dst {
+            if (dst_chain, dst_idx) == (src_chain, src_idx) {
+                PackEntry::validate_name(new_name, self.encoding)?;
+                let chain = self.get_chain_mut(src_chain).unwrap();
+                chain.rename_entry(src_idx, new_name.to_owned());
+                crate::io::write_chain_entry(
+                    self.blowfish.as_ref(),
+                    self.encoding,
+                    self.file.get_mut(),
+                    self.block_manager.get(src_chain).unwrap(),
+                    src_idx,
+                )?;
+                self.file.commit()?;
+                return Ok(());
+            }
+        }
+        // a directory can't be moved into itself or one of its subdirectories
+        if let Some(moved_chain) = moved_chain {
+            let (mut chain, _) = self
+                .block_manager
+                .validate_dir_path_until(PK2_ROOT_BLOCK, to)?;
+            loop {
+                if chain == moved_chain {
+                    return Err(Error::InvalidPath);
+                }
+                match self
+                    .get_chain(chain)
+                    .ok_or(Error::InvalidChainIndex)?
+                    .find_block_chain_index_of(PK2_PARENT_DIR_IDENT)
+                {
+                    Ok(parent) => chain = parent,
+                    Err(Error::NotFound) => break,
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+
+        let times = self.now_times();
+        let (dst_chain, dst_idx) = Self::create_entry_at(
+            &mut self.block_manager,
+            self.blowfish.as_ref(),
+            self.encoding,
+            &mut self.free_list,
+            self.file.get_mut(),
+            PK2_ROOT_BLOCK,
+            to,
+            times,
+        )?;
+        // the source entry is cleared first so that it doesn't collide with
+        // the moved entry in the name index if both share a directory
+        self.get_chain_mut(src_chain).unwrap().clear_entry(src_idx);
+        let mut entry = entry;
+        let dst = self.get_chain_mut(dst_chain).unwrap();
+        let next_block = dst[dst_idx].next_block().map_or(0, NonZeroU64::get);
+        entry.set_next_block(BlockOffset(next_block));
+        dst.set_entry(dst_idx, entry);
+        dst.rename_entry(dst_idx, new_name.to_owned());
+        crate::io::write_chain_entry(
+            self.blowfish.as_ref(),
+            self.encoding,
+            self.file.get_mut(),
+            self.block_manager.get(dst_chain).unwrap(),
+            dst_idx,
+        )?;
+        crate::io::write_chain_entry(
+            self.blowfish.as_ref(),
+            self.encoding,
+            self.file.get_mut(),
+            self.block_manager.get(src_chain).unwrap(),
+            src_idx,
+        )?;
+
+        // point the parent link of the moved directory to its new parent
+        if let Some(moved_chain) = moved_chain {
+            self.load_chain(moved_chain)?;
+            let chain = self
+                .block_manager
+                .get_mut(moved_chain)
+                .ok_or(Error::InvalidChainIndex)?;
+            let parent_idx = chain
+                .entries()
+                .position(|entry| match entry {
+                    PackEntry::Directory(dir) => dir.is_parent_link(),
+                    _ => false,
+                })
+                .ok_or(Error::CorruptedFile)?;
+            if let Some(dir) = chain
+                .get_mut(parent_idx)
+                .and_then(PackEntry::as_directory_mut)
+            {
+                dir.set_children_position(dst_chain);
+            }
+            crate::io::write_chain_entry(
+                self.blowfish.as_ref(),
+                self.encoding,
+                self.file.get_mut(),
+                chain,
+                parent_idx,
+            )?;
+        }
+        self.file.commit()?;
         Ok(())
     }
 
     pub fn create_file<P: AsRef<Path>>(&mut self, path: P) -> Pk2Result<FileMut<B>> {
-        let path = check_root(path.as_ref())?;
+        let (chain, entry_idx) = self.create_file_impl(path.as_ref())?;
+        Ok(FileMut::new(self, chain, entry_idx))
+    }
+
+    /// Creates a new file whose data gets written directly to the end of the
+    /// archive as it arrives instead of being buffered in memory. The entry's
+    /// size is only updated when the returned [`FileStream`] is flushed or
+    /// dropped. Errors that occur when flushing on drop are discarded.
+    pub fn create_file_streaming<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+    ) -> Pk2Result<FileStream<'_, B>> {
+        let (chain, entry_idx) = self.create_file_impl(path.as_ref())?;
+        let pos_data = crate::io::file_len(self.file.get_mut())?;
+        if let Some(file) = self
+            .get_entry_mut(chain, entry_idx)
+            .and_then(PackEntry::as_file_mut)
+        {
+            file.pos_data = pos_data;
+        }
+        Ok(FileStream::new(self, chain, entry_idx))
+    }
+
+    fn create_file_impl(&mut self, path: &Path) -> Pk2Result<(ChainIndex, usize)> {
+        self.file.begin()?;
+        let path = check_root(path)?;
         let file_name = path
             .file_name()
             .ok_or(Error::InvalidPath)?
             .to_str()
             .ok_or(Error::NonUnicodePath)?;
+        self.load_path(PK2_ROOT_BLOCK, path)?;
+        let times = self.now_times();
         let (chain, entry_idx) = Self::create_entry_at(
             &mut self.block_manager,
             self.blowfish.as_ref(),
-            &mut *self.file.borrow_mut(),
+            self.encoding,
+            &mut self.free_list,
+            self.file.get_mut(),
             PK2_ROOT_BLOCK,
             path,
+            times,
         )?;
-        let entry = self.get_entry_mut(chain, entry_idx).unwrap();
-        *entry = PackEntry::new_file(file_name, 0, 0, entry.next_block());
-        Ok(FileMut::new(self, chain, entry_idx))
+        let chain_ref = self.get_chain_mut(chain).unwrap();
+        let next_block = chain_ref[entry_idx].next_block();
+        let mut entry = PackEntry::new_file(file_name, 0, 0, next_block);
+        times.apply(&mut entry);
+        chain_ref.set_entry(entry_idx, entry);
+        self.file.commit()?;
+        Ok((chain, entry_idx))
+    }
+
+    /// Creates a new, empty directory at the given path. The parent directory
+    /// has to exist already.
+    pub fn create_directory<P: AsRef<Path>>(&mut self, path: P) -> Pk2Result<Directory<'_, B>> {
+        let path = check_root(path.as_ref())?;
+        let parent = path.parent().ok_or(Error::AlreadyExists)?;
+        self.load_path(PK2_ROOT_BLOCK, parent)?;
+        self.block_manager
+            .resolve_path_to_block_chain_index_at(PK2_ROOT_BLOCK, parent)?;
+        let (chain, entry_idx) = self.create_directory_impl(path)?;
+        Ok(Directory::new(self, chain, entry_idx))
+    }
+
+    /// Creates a new directory at the given path including all of its missing
+    /// parent directories. Returns the directory if it exists already.
+    pub fn create_dir_all<P: AsRef<Path>>(&mut self, path: P) -> Pk2Result<Directory<'_, B>> {
+        let path = check_root(path.as_ref())?;
+        match self.create_directory_impl(path) {
+            Ok((chain, entry_idx)) => Ok(Directory::new(self, chain, entry_idx)),
+            Err(Error::AlreadyExists) => {
+                self.open_directory(Path::new("/").join(path))
+                    .map_err(|e| match e {
+                        Error::ExpectedDirectory => Error::AlreadyExists,
+                        e => e,
+                    })
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    fn create_directory_impl(&mut self, path: &Path) -> Pk2Result<(ChainIndex, usize)> {
+        self.file.begin()?;
+        self.load_path(PK2_ROOT_BLOCK, path)?;
+        let times = self.now_times();
+        let (chain, entry_idx) = Self::create_entry_at(
+            &mut self.block_manager,
+            self.blowfish.as_ref(),
+            self.encoding,
+            &mut self.free_list,
+            self.file.get_mut(),
+            PK2_ROOT_BLOCK,
+            path,
+            times,
+        )?;
+        let dir_name = path
+            .file_name()
+            .ok_or(Error::InvalidPath)?
+            .to_str()
+            .ok_or(Error::NonUnicodePath)?;
+        let block_chain = crate::io::allocate_new_block_chain(
+            self.blowfish.as_ref(),
+            self.encoding,
+            &mut self.free_list,
+            self.file.get_mut(),
+            self.block_manager.get_mut(chain).unwrap(),
+            dir_name,
+            entry_idx,
+            times,
+        )?;
+        self.block_manager
+            .insert(block_chain.chain_index(), block_chain);
+        self.file.commit()?;
+        Ok((chain, entry_idx))
     }
 
     /// This function traverses the whole path creating anything that does not
     /// yet exist returning the last created entry. This means using parent and
     /// current dir parts in a path that in the end directs to an already
     /// existing path might still create new directories.
+    #[allow(clippy::too_many_arguments)]
     fn create_entry_at(
         block_manager: &mut BlockManager,
         blowfish: Option<&Blowfish>,
-        mut file: &mut B,
+        encoding: Encoding,
+        free_list: &mut FreeList,
+        mut file: impl io::Write + io::Seek,
         chain: ChainIndex,
         path: &Path,
+        times: FileTimes,
     ) -> Pk2Result<(ChainIndex, usize)> {
         use crate::io::{allocate_empty_block, allocate_new_block_chain, write_chain_entry};
         let (mut current_chain_index, mut components) =
             block_manager.validate_dir_path_until(chain, path)?;
+        // validate every name up front so that nothing gets created for an invalid path
+        for component in components.clone() {
+            if let Component::Normal(p) = component {
+                let name = p.to_str().ok_or(Error::NonUnicodePath)?;
+                PackEntry::validate_name(name, encoding)?;
+            }
+        }
         while let Some(component) = components.next() {
             match component {
                 Component::Normal(p) => {
@@ -288,11 +953,13 @@ where
                     } else {
                         // current chain is full so create a new block and append it
                         //current_chain.create_new_block(blowfish, &mut file)?
-                        let (offset, block) = allocate_empty_block(blowfish, &mut file)?;
+                        let (offset, block) =
+                            allocate_empty_block(blowfish, encoding, free_list, &mut file)?;
                         let chain_entry_idx = current_chain.num_entries();
                         current_chain.push_and_link(offset, block);
                         write_chain_entry(
                             blowfish,
+                            encoding,
                             &mut file,
                             &current_chain,
                             chain_entry_idx - 1,
@@ -305,10 +972,13 @@ where
                         let dir_name = p.to_str().ok_or(Error::NonUnicodePath)?;
                         let block_chain = allocate_new_block_chain(
                             blowfish,
+                            encoding,
+                            free_list,
                             &mut file,
                             current_chain,
                             dir_name,
                             chain_entry_idx,
+                            times,
                         )?;
                         current_chain_index = block_chain.chain_index();
                         block_manager.insert(current_chain_index, block_chain);
@@ -328,9 +998,1038 @@ where
         }
         Err(Error::AlreadyExists)
     }
+
+    /// Re-encrypts the index of the archive with `new_key` in place. File
+    /// data isn't encrypted and stays untouched. An empty key turns the
+    /// archive into an unencrypted one, a non-empty key encrypts a previously
+    /// unencrypted archive.
+    ///
+    /// Unless the archive is journaled, this is not atomic, if the process
+    /// gets interrupted while rekeying the archive will most likely be
+    /// corrupted.
+    pub fn rekey<K: AsRef<[u8]>>(&mut self, new_key: K) -> Pk2Result<()> {
+        self.rekey_with_key(Key::from(new_key.as_ref()))
+    }
+
+    /// Like [`Pk2::rekey`], but takes a [`Key`] which allows for custom salts
+    /// and raw keys.
+    pub fn rekey_with_key(&mut self, new_key: Key<'_>) -> Pk2Result<()> {
+        self.file.begin()?;
+        use std::io::Seek;
+        self.load_all()?;
+        let blowfish = new_key.blowfish()?;
+        let mut file = self.file.get_mut();
+        file.seek(io::SeekFrom::Start(0))?;
+        let mut header = PackHeader::from_reader(&mut file)?;
+        header.encrypted = blowfish.is_some();
+        header.verify = *PK2_CHECKSUM;
+        if let Some(bf) = &blowfish {
+            bf.encrypt(&mut header.verify);
+        }
+        file.seek(io::SeekFrom::Start(0))?;
+        header.to_writer(&mut file)?;
+        for chain in self.block_manager.chains() {
+            for (offset, block) in chain.blocks() {
+                crate::io::write_block(blowfish.as_ref(), self.encoding, &mut file, offset, block)?;
+            }
+        }
+        self.blowfish = blowfish;
+        self.file.commit()?;
+        Ok(())
+    }
+}
+
+impl<B> Pk2<B>
+where
+    B: io::Read + io::Write + io::Seek + Truncate,
+{
+    /// Moves all blocks and file data towards the start of the archive,
+    /// closing the gaps left behind by deleted or relocated entries, and
+    /// truncates the archive afterwards. Returns the new size of the archive.
+    ///
+    /// Fails with [`Error::CorruptedFile`] before modifying anything if the
+    /// data of a file or a block lies outside of the archive.
+    /// [`Pk2::check`] names the affected entries and [`Pk2::repair`] removes
+    /// them.
+    ///
+    /// Unless the archive is journaled, this is not atomic, if the process
+    /// gets interrupted while compacting the archive will most likely be
+    /// corrupted.
+    pub fn compact(&mut self) -> Pk2Result<u64> {
+        use self::check::Problem;
+        use std::io::{Read, Seek, Write};
+        self.file.begin()?;
+        self.load_all()?;
+        let out_of_bounds = self.check()?.problems.iter().any(|problem| {
+            matches!(
+                problem,
+                Problem::DataOutOfBounds { .. } | Problem::BlockOutOfBounds { .. }
+            )
+        });
+        if out_of_bounds {
+            return Err(Error::CorruptedFile);
+        }
+        let header_region = (0, PK2_ROOT_BLOCK.0);
+        let mut regions: Vec<_> = self
+            .block_manager
+            .used_regions()
+            .chain(Some(header_region))
+            .filter(|&(_, len)| len != 0)
+            .collect();
+        regions.sort_unstable();
+        // merge overlapping and adjacent regions into spans of (start, end, new_start)
+        let mut spans: Vec<(u64, u64, u64)> = Vec::new();
+        for (offset, len) in regions {
+            match spans.last_mut() {
+                Some((_, end, _)) if offset <= *end => *end = (*end).max(offset + len),
+                _ => spans.push((offset, offset + len, 0)),
+            }
+        }
+        let mut new_len = 0;
+        for (start, end, new_start) in &mut spans {
+            *new_start = new_len;
+            new_len += *end - *start;
+        }
+
+        let mut file = self.file.get_mut();
+        // spans only ever move towards the start of the file, so copying them front to
+        // back never overwrites anything that hasn't been moved yet
+        let mut buf = vec![0; 0x10000];
+        for &(start, end, new_start) in spans.iter().filter(|&&(start, _, new)| start != new) {
+            let mut copied = 0;
+            while copied < end - start {
+                let len = buf.len().min((end - start - copied) as usize);
+                file.seek(io::SeekFrom::Start(start + copied))?;
+                file.read_exact(&mut buf[..len])?;
+                file.seek(io::SeekFrom::Start(new_start + copied))?;
+                file.write_all(&buf[..len])?;
+                copied += len as u64;
+            }
+        }
+
+        self.block_manager.relocate(|offset| {
+            let idx = match spans.binary_search_by_key(&offset, |&(start, _, _)| start) {
+                Ok(idx) => idx,
+                Err(idx) => idx.saturating_sub(1),
+            };
+            match spans.get(idx) {
+                Some(&(start, end, new_start)) if start <= offset && offset < end => {
+                    offset - start + new_start
+                }
+                _ => offset,
+            }
+        });
+        for chain in self.block_manager.chains() {
+            for (offset, block) in chain.blocks() {
+                crate::io::write_block(
+                    self.blowfish.as_ref(),
+                    self.encoding,
+                    &mut file,
+                    offset,
+                    block,
+                )?;
+            }
+        }
+        // truncation can't be undone, so everything before it is committed first
+        self.file.commit()?;
+        self.file.get_mut().truncate(new_len)?;
+        self.file.commit()?;
+        self.free_list = FreeList::new();
+        Ok(new_len)
+    }
 }
 
 #[inline]
 fn check_root(path: &Path) -> Pk2Result<&Path> {
     path.strip_prefix("/").map_err(|_| Error::InvalidPath)
 }
+
+#[test]
+fn test_concurrent_reads() {
+    use std::io::{Read, Write};
+    let mut archive = Pk2::create_new_in(io::Cursor::new(Vec::new()), "169841").unwrap();
+    for i in 0..8u8 {
+        let mut file = archive.create_file(format!("/{}.bin", i)).unwrap();
+        file.write_all(&[i; 4096]).unwrap();
+    }
+    let archive = &archive;
+    std::thread::scope(|scope| {
+        for i in 0..8u8 {
+            scope.spawn(move || {
+                for _ in 0..64 {
+                    let mut buf = Vec::new();
+                    let mut file = archive.open_file(format!("/{}.bin", i)).unwrap();
+                    file.read_to_end(&mut buf).unwrap();
+                    assert!(buf.iter().all(|&b| b == i));
+                }
+            });
+        }
+    });
+}
+
+#[test]
+fn test_walk() {
+    let mut archive = Pk2::create_new_in(io::Cursor::new(Vec::new()), "").unwrap();
+    for path in &["/a/x/1", "/a/x/y/2", "/a/z/3", "/a/4", "/b/5"] {
+        archive.create_file(path).unwrap();
+    }
+    let paths = |walk: Walk<_>| {
+        walk.map(|entry| entry.unwrap().0.to_str().unwrap().to_owned())
+            .collect::<Vec<_>>()
+    };
+    let mut files = Vec::new();
+    archive
+        .for_each_file("/a", |path, _| {
+            files.push(path.to_str().unwrap().to_owned());
+            Ok(())
+        })
+        .unwrap();
+    files.sort();
+    assert_eq!(files, ["4", "x/1", "x/y/2", "z/3"]);
+
+    let walked = paths(archive.walk("/").unwrap().contents_first(true));
+    let pos = |p: &str| walked.iter().position(|w| w == p).unwrap();
+    assert_eq!(walked.len(), 10);
+    assert!(pos("/a/x/y/2") < pos("/a/x/y") && pos("/a/x/y") < pos("/a/x"));
+    assert!(pos("/a/x") < pos("/a") && pos("/a/4") < pos("/a"));
+
+    let walked = paths(archive.walk("/").unwrap().breadth_first(true));
+    let depths: Vec<_> = walked.iter().map(|p| p.matches('/').count()).collect();
+    assert!(depths.windows(2).all(|w| w[0] <= w[1]));
+
+    let walked = paths(archive.walk("/a").unwrap().max_depth(1));
+    assert_eq!(walked.len(), 3);
+
+    let mut walk = archive.walk("/").unwrap();
+    let mut walked = Vec::new();
+    while let Some(entry) = walk.next() {
+        let (path, _) = entry.unwrap();
+        if path.ends_with("x") {
+            walk.skip_subtree();
+        }
+        walked.push(path);
+    }
+    assert_eq!(walked.len(), 7);
+}
+
+#[test]
+fn test_walk_events() {
+    use self::fs::WalkEvent;
+    let mut archive = Pk2::create_new_in(io::Cursor::new(Vec::new()), "").unwrap();
+    for path in &["/a/x/1", "/a/2", "/b/3"] {
+        archive.create_file(path).unwrap();
+    }
+    let events = |events: fs::WalkEvents<_>| {
+        events
+            .map(|event| match event.unwrap() {
+                WalkEvent::Enter(path, _) => format!("+{}", path.display()),
+                WalkEvent::Exit(path, _) => format!("-{}", path.display()),
+            })
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(
+        events(archive.walk("/").unwrap().events()),
+        ["+/a", "+/a/x", "+/a/x/1", "-/a/x", "+/a/2", "-/a", "+/b", "+/b/3", "-/b"]
+    );
+    assert_eq!(
+        events(archive.walk("/").unwrap().max_depth(1).events()),
+        ["+/a", "-/a", "+/b", "-/b"]
+    );
+    let mut walk = archive.walk("/a").unwrap().events();
+    let mut walked = Vec::new();
+    while let Some(event) = walk.next() {
+        if let WalkEvent::Enter(path, _) = event.unwrap() {
+            if path.ends_with("x") {
+                walk.skip_subtree();
+            }
+            walked.push(path);
+        }
+    }
+    assert_eq!(walked, [Path::new("/a/x"), Path::new("/a/2")]);
+}
+
+#[test]
+fn test_walk_cycle() {
+    let mut buf = io::Cursor::new(Vec::new());
+    let mut archive = Pk2::create_new_in(&mut buf, "").unwrap();
+    archive.create_dir_all("/a/b/c").unwrap();
+    archive.create_directory("/d").unwrap();
+    let a = chain_of(&archive, "/a");
+    drop(archive);
+    // point /a/b back at /a
+    patch_entry(buf.get_mut(), 1, "b", 106, &a.to_le_bytes());
+    let archive = Pk2::open_in(&mut buf, "").unwrap();
+    let walked: Vec<_> = archive
+        .walk("/")
+        .unwrap()
+        .map(|entry| entry.unwrap().0)
+        .collect();
+    assert_eq!(
+        walked,
+        [Path::new("/a"), Path::new("/a/b"), Path::new("/d")]
+    );
+}
+
+#[test]
+fn test_invalid_names() {
+    let mut archive = Pk2::create_new_in(io::Cursor::new(Vec::new()), "").unwrap();
+    let long = "a".repeat(81);
+    assert!(matches!(
+        archive.create_file(format!("/dir/{}", long)),
+        Err(Error::InvalidName)
+    ));
+    // nothing has been created for the rejected path
+    assert!(archive.open_directory("/dir").is_err());
+    assert!(matches!(
+        archive.create_file("/a\\b"),
+        Err(Error::InvalidName)
+    ));
+    assert!(archive.create_file(format!("/{}", &long[1..])).is_ok());
+}
+
+#[test]
+fn test_name_index() {
+    let mut archive = Pk2::create_new_in(io::Cursor::new(Vec::new()), "").unwrap();
+    for i in 0..50 {
+        archive.create_file(format!("/dir/File{}", i)).unwrap();
+    }
+    archive.delete_file("/dir/file3").unwrap();
+    archive.rename("/dir/FILE4", "/dir/moved").unwrap();
+    archive.rename("/dir/file5", "/other/file5").unwrap();
+    assert!(archive.open_file("/dir/file3").is_err());
+    assert!(archive.open_file("/dir/file4").is_err());
+    assert!(archive.open_file("/dir/MOVED").is_ok());
+    assert!(archive.open_file("/other/file5").is_ok());
+    assert!(archive.open_file("/DIR/file49").is_ok());
+    archive.create_file("/dir/file3").unwrap();
+    assert!(archive.open_file("/dir/file3").is_ok());
+}
+
+#[test]
+fn test_rekey() {
+    let mut buf = io::Cursor::new(Vec::new());
+    let mut archive = Pk2::create_new_in(&mut buf, "169841").unwrap();
+    for i in 0..30 {
+        archive.create_file(format!("/dir/{}", i)).unwrap();
+    }
+    archive.rekey("").unwrap();
+    drop(archive);
+    let mut archive = Pk2::open_in(&mut buf, "anything").unwrap();
+    archive.rekey("other").unwrap();
+    drop(archive);
+    assert!(matches!(
+        Pk2::open_in(&mut buf, "169841"),
+        Err(Error::InvalidKey)
+    ));
+    let archive = Pk2::open_in(&mut buf, "other").unwrap();
+    assert!(archive.open_file("/dir/29").is_ok());
+}
+
+#[test]
+fn test_custom_keys() {
+    let salted = Key::Salted {
+        key: b"169841",
+        salt: &[0x01, 0x02, 0x03, 0x04, 0x05],
+    };
+    let mut buf = io::Cursor::new(Vec::new());
+    let archive = OpenOptions::new()
+        .create_new_in_with_key(&mut buf, salted)
+        .unwrap();
+    drop(archive);
+    assert!(matches!(
+        Pk2::open_in(&mut buf, "169841"),
+        Err(Error::InvalidKey)
+    ));
+    assert!(OpenOptions::new()
+        .open_in_with_key(&mut buf, salted)
+        .is_ok());
+
+    let mut buf = io::Cursor::new(Vec::new());
+    let archive = OpenOptions::new()
+        .create_new_in_with_key(&mut buf, Key::Raw(b"derived"))
+        .unwrap();
+    drop(archive);
+    assert!(matches!(
+        Pk2::open_in(&mut buf, "derived"),
+        Err(Error::InvalidKey)
+    ));
+    assert!(OpenOptions::new()
+        .open_in_with_key(&mut buf, Key::Raw(b"derived"))
+        .is_ok());
+}
+
+#[test]
+fn test_owned_keys() {
+    let mut buf = io::Cursor::new(Vec::new());
+    let key = b"169841".to_vec();
+    drop(Pk2::create_new_in(&mut buf, &key).unwrap());
+    assert!(Pk2::open_in(&mut buf, String::from("169841")).is_ok());
+}
+
+#[test]
+fn test_custom_signature() {
+    let mut buf = io::Cursor::new(Vec::new());
+    let archive = OpenOptions::new()
+        .signature("Custom File Manager!\n")
+        .version(0x0100_0003)
+        .create_new_in(&mut buf, "169841")
+        .unwrap();
+    drop(archive);
+    assert!(matches!(
+        Pk2::open_in(&mut buf, "169841"),
+        Err(Error::CorruptedFile)
+    ));
+    assert!(matches!(
+        OpenOptions::new()
+            .accept_signature("Custom File Manager!\n")
+            .open_in(&mut buf, "169841"),
+        Err(Error::UnsupportedVersion)
+    ));
+    assert!(OpenOptions::new()
+        .accept_signature("Custom File Manager!\n")
+        .accept_version(0x0100_0003)
+        .open_in(&mut buf, "169841")
+        .is_ok());
+    assert!(OpenOptions::new()
+        .ignore_signature(true)
+        .open_in(&mut buf, "169841")
+        .is_ok());
+}
+
+#[test]
+fn test_signature_too_long() {
+    let too_long = [b'!'; 31];
+    let mut buf = io::Cursor::new(Vec::new());
+    assert!(matches!(
+        OpenOptions::new()
+            .signature(too_long)
+            .create_new_in(&mut buf, "169841"),
+        Err(Error::InvalidSignature)
+    ));
+    drop(Pk2::create_new_in(&mut buf, "169841").unwrap());
+    assert!(matches!(
+        OpenOptions::new()
+            .accept_signature(too_long)
+            .open_in(&mut buf, "169841"),
+        Err(Error::InvalidSignature)
+    ));
+    assert!(OpenOptions::new()
+        .signature([b'!'; 30])
+        .create_new_in(io::Cursor::new(Vec::new()), "169841")
+        .is_ok());
+}
+
+#[test]
+fn test_reproducible_output() {
+    use std::io::Write;
+    use std::time::{Duration, SystemTime};
+    let time = SystemTime::UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+    let mut options = OpenOptions::new();
+    options.fixed_time(Some(time));
+
+    let create = || {
+        let mut buf = io::Cursor::new(Vec::new());
+        let mut archive = options.create_new_in(&mut buf, "169841").unwrap();
+        archive.create_dir_all("/a/b").unwrap();
+        let mut file = archive.create_file("/a/c/file.txt").unwrap();
+        file.write_all(b"contents").unwrap();
+        drop(file);
+        drop(archive);
+        buf.into_inner()
+    };
+    assert_eq!(create(), create());
+
+    let build = |paths: &[&'static str]| {
+        let mut builder = Pk2Builder::with_options(&options);
+        for path in paths {
+            builder
+                .add_file(path, path.as_bytes(), FileTimes::new(time))
+                .unwrap();
+        }
+        let mut buf = io::Cursor::new(Vec::new());
+        builder.build_in(&mut buf, "169841").unwrap();
+        buf.into_inner()
+    };
+    assert_eq!(
+        build(&["/x/1", "/y/2", "/x/3"]),
+        build(&["/x/3", "/y/2", "/x/1"])
+    );
+}
+
+#[test]
+fn test_root_times_after_reopen() {
+    use std::time::{Duration, SystemTime};
+    let time = SystemTime::UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+    let mut builder = Pk2Builder::new();
+    builder.add_directory("/", FileTimes::new(time)).unwrap();
+    builder.add_file("/a", &b"a"[..], FileTimes::now()).unwrap();
+    let mut buf = io::Cursor::new(Vec::new());
+    drop(builder.build_in(&mut buf, "169841").unwrap());
+    for &lazy in &[false, true] {
+        let archive = OpenOptions::new()
+            .lazy(lazy)
+            .open_in(&mut buf, "169841")
+            .unwrap();
+        let times = archive.open_directory("/").unwrap().times();
+        assert_eq!(times, FileTimes::new(time));
+    }
+}
+
+#[test]
+fn test_delete_reuses_space() {
+    use std::io::{Read, Write};
+    let mut buf = io::Cursor::new(Vec::new());
+    let mut archive = Pk2::create_new_in(&mut buf, "169841").unwrap();
+    archive
+        .create_file("/a")
+        .unwrap()
+        .write_all(&[1; 4096])
+        .unwrap();
+    archive
+        .create_file("/b")
+        .unwrap()
+        .write_all(&[2; 16])
+        .unwrap();
+    drop(archive);
+    let len = buf.get_ref().len();
+
+    let mut archive = Pk2::open_in(&mut buf, "169841").unwrap();
+    archive.delete_file("/a").unwrap();
+    archive
+        .create_file("/c")
+        .unwrap()
+        .write_all(&[3; 4096])
+        .unwrap();
+    let mut data = Vec::new();
+    archive
+        .open_file("/c")
+        .unwrap()
+        .read_to_end(&mut data)
+        .unwrap();
+    assert_eq!(data, [3; 4096]);
+    assert!(matches!(archive.open_file("/a"), Err(Error::NotFound)));
+    drop(archive);
+    assert_eq!(buf.get_ref().len(), len);
+}
+
+/// Overwrites bytes of the entry with the given type and name in the
+/// unencrypted archive `buf` at `field`, used to craft damaged archives.
+#[cfg(test)]
+pub(crate) fn patch_entry(buf: &mut [u8], ty: u8, name: &str, field: usize, bytes: &[u8]) {
+    let mut pattern = vec![ty];
+    pattern.extend_from_slice(name.as_bytes());
+    pattern.push(0);
+    let offset = buf
+        .windows(pattern.len())
+        .position(|window| window == &pattern[..])
+        .unwrap_or_else(|| panic!("no entry named {:?}", name));
+    buf[offset + field..][..bytes.len()].copy_from_slice(bytes);
+}
+
+/// Returns the offset of the chain of the directory at `path`.
+#[cfg(test)]
+pub(crate) fn chain_of<B: io::Read + io::Seek>(archive: &Pk2<B>, path: &str) -> u64 {
+    let (_, _, entry) = archive.root_resolve_path_to_entry_and_parent(path).unwrap();
+    entry.as_directory().unwrap().children_position().0
+}
+
+/// Damages the bytes of an archive, given the archive they were taken from.
+#[cfg(test)]
+pub(crate) type Damage = fn(&Pk2<&mut io::Cursor<Vec<u8>>>, &mut Vec<u8>);
+
+/// Creates an unencrypted archive containing `paths` and returns its bytes
+/// after applying `damage` to them. Paths ending in `/` are created as
+/// directories, files contain 100 bytes of data.
+#[cfg(test)]
+pub(crate) fn damaged_archive(paths: &[&str], damage: Damage) -> io::Cursor<Vec<u8>> {
+    use std::io::Write;
+    let mut buf = io::Cursor::new(Vec::new());
+    let mut archive = Pk2::create_new_in(&mut buf, "").unwrap();
+    for path in paths {
+        if path.ends_with('/') {
+            archive.create_directory(path).unwrap();
+        } else {
+            archive
+                .create_file(path)
+                .unwrap()
+                .write_all(&[1; 100])
+                .unwrap();
+        }
+    }
+    let mut patched = archive.file.lock().get_ref().clone();
+    damage(&archive, &mut patched);
+    io::Cursor::new(patched)
+}
+
+#[test]
+fn test_create_over_existing() {
+    use std::io::Write;
+    let mut buf = io::Cursor::new(Vec::new());
+    let mut archive = Pk2::create_new_in(&mut buf, "169841").unwrap();
+    archive
+        .create_file("/a.txt")
+        .unwrap()
+        .write_all(b"a")
+        .unwrap();
+    archive.create_directory("/dir").unwrap();
+    assert!(matches!(
+        archive.create_directory("/a.txt"),
+        Err(Error::AlreadyExists)
+    ));
+    assert!(matches!(
+        archive.create_dir_all("/a.txt"),
+        Err(Error::AlreadyExists)
+    ));
+    assert!(matches!(
+        archive.create_directory("/dir"),
+        Err(Error::AlreadyExists)
+    ));
+    assert!(matches!(
+        archive.create_file("/a.txt"),
+        Err(Error::AlreadyExists)
+    ));
+    assert!(matches!(
+        archive.create_dir_all("/a.txt/b"),
+        Err(Error::ExpectedDirectory)
+    ));
+    assert!(archive.create_dir_all("/dir").is_ok());
+}
+
+#[test]
+fn test_delete_directory() {
+    use std::io::Write;
+    let mut buf = io::Cursor::new(Vec::new());
+    let mut archive = Pk2::create_new_in(&mut buf, "169841").unwrap();
+    for path in &["/a/b/c/1", "/a/b/2", "/a/3", "/keep/4"] {
+        archive
+            .create_file(path)
+            .unwrap()
+            .write_all(&[7; 1000])
+            .unwrap();
+    }
+    assert!(matches!(
+        archive.delete_directory("/a"),
+        Err(Error::DirectoryNotEmpty)
+    ));
+    archive.delete_directory_all("/a").unwrap();
+    assert!(matches!(archive.open_directory("/a"), Err(Error::NotFound)));
+    assert!(archive.open_file("/keep/4").is_ok());
+    assert!(archive.check().unwrap().is_ok());
+    drop(archive);
+    let len = buf.get_ref().len();
+
+    // the freed blocks and data get reused
+    let mut archive = Pk2::open_in(&mut buf, "169841").unwrap();
+    for path in &["/x/y/z/1", "/x/y/2", "/x/3"] {
+        archive
+            .create_file(path)
+            .unwrap()
+            .write_all(&[8; 1000])
+            .unwrap();
+    }
+    assert!(archive.check().unwrap().is_ok());
+    drop(archive);
+    assert_eq!(buf.get_ref().len(), len);
+}
+
+#[test]
+fn test_delete_directory_cycle() {
+    let mut buf = io::Cursor::new(Vec::new());
+    let mut archive = Pk2::create_new_in(&mut buf, "").unwrap();
+    archive.create_file("/a/b/file").unwrap();
+    drop(archive);
+    // point /a/b back at the root
+    let root = PK2_ROOT_BLOCK.0.to_le_bytes();
+    patch_entry(buf.get_mut(), 1, "b", 106, &root);
+    let mut archive = Pk2::open_in(&mut buf, "").unwrap();
+    assert!(matches!(
+        archive.delete_directory_all("/a"),
+        Err(Error::CorruptedFile)
+    ));
+    assert!(archive.open_directory("/a").is_ok());
+}
+
+#[test]
+fn test_rename() {
+    use std::io::{Read, Write};
+    let mut buf = io::Cursor::new(Vec::new());
+    let mut archive = Pk2::create_new_in(&mut buf, "169841").unwrap();
+    for path in &["/a/b/1", "/a/b/c/2", "/a/3"] {
+        archive
+            .create_file(path)
+            .unwrap()
+            .write_all(path.as_bytes())
+            .unwrap();
+    }
+    // move a file into another directory
+    archive.rename("/a/3", "/x/3").unwrap();
+    // move a directory along with its contents
+    archive.rename("/a/b", "/x/y/b2").unwrap();
+    assert!(matches!(
+        archive.rename("/x/y", "/x/y/b2/y"),
+        Err(Error::InvalidPath)
+    ));
+    // renaming an entry to itself does nothing, unless the case changes
+    archive.rename("/x/3", "/x/3").unwrap();
+    archive.rename("/x/y/b2/c", "/x/y/B2/C").unwrap();
+    assert!(matches!(
+        archive.open_directory("/a/b"),
+        Err(Error::NotFound)
+    ));
+    assert!(matches!(archive.open_file("/a/3"), Err(Error::NotFound)));
+    assert!(archive.check().unwrap().is_ok());
+    drop(archive);
+
+    let archive = Pk2::open_in(&mut buf, "169841").unwrap();
+    for (path, contents) in &[
+        ("/x/3", "/a/3"),
+        ("/x/y/b2/1", "/a/b/1"),
+        ("/x/y/b2/c/2", "/a/b/c/2"),
+    ] {
+        let mut read = String::new();
+        archive
+            .open_file(path)
+            .unwrap()
+            .read_to_string(&mut read)
+            .unwrap();
+        assert_eq!(read, *contents);
+    }
+    assert_eq!(archive.open_directory("/x/y/b2/c").unwrap().name(), "C");
+    // the parent link of the moved directory points at its new parent
+    assert!(archive.open_file("/x/y/b2/c/../1").is_ok());
+    assert!(archive.check().unwrap().is_ok());
+}
+
+#[test]
+fn test_file_stream() {
+    use std::io::{Read, Write};
+    let mut buf = io::Cursor::new(Vec::new());
+    let mut archive = Pk2::create_new_in(&mut buf, "169841").unwrap();
+    let data: Vec<u8> = (0..3 * PK2_FILE_BLOCK_SIZE as u32)
+        .map(|i| (i % 251) as u8)
+        .collect();
+    let mut stream = archive.create_file_streaming("/dir/stream").unwrap();
+    for chunk in data.chunks(1000) {
+        stream.write_all(chunk).unwrap();
+    }
+    stream.flush().unwrap();
+    drop(stream);
+    archive
+        .create_file("/dir/after")
+        .unwrap()
+        .write_all(b"after")
+        .unwrap();
+    drop(archive);
+
+    let archive = Pk2::open_in(&mut buf, "169841").unwrap();
+    let mut read = Vec::new();
+    archive
+        .open_file("/dir/stream")
+        .unwrap()
+        .read_to_end(&mut read)
+        .unwrap();
+    assert_eq!(read, data);
+    let mut read = Vec::new();
+    archive
+        .open_file("/dir/after")
+        .unwrap()
+        .read_to_end(&mut read)
+        .unwrap();
+    assert_eq!(read, b"after");
+    assert!(archive.check().unwrap().is_ok());
+}
+
+#[test]
+fn test_compact() {
+    use std::io::{Read, Seek, Write};
+    fn tree<B: io::Read + io::Seek>(archive: &Pk2<B>) -> Vec<std::path::PathBuf> {
+        archive
+            .walk("/")
+            .unwrap()
+            .map(|entry| entry.unwrap().0)
+            .collect()
+    }
+    for &key in &["", "169841"] {
+        let mut buf = io::Cursor::new(Vec::new());
+        let mut archive = Pk2::create_new_in(&mut buf, key).unwrap();
+        for i in 0..30 {
+            archive
+                .create_file(format!("/dir{}/file{}", i % 3, i))
+                .unwrap()
+                .write_all(&vec![i as u8; 500 + i])
+                .unwrap();
+        }
+        for i in (0..30).step_by(4) {
+            archive
+                .delete_file(format!("/dir{}/file{}", i % 3, i))
+                .unwrap();
+        }
+        archive.delete_directory_all("/dir2").unwrap();
+        let before = tree(&archive);
+        let old_len = archive.file.get_mut().seek(io::SeekFrom::End(0)).unwrap();
+        let len = archive.compact().unwrap();
+        assert!(len < old_len);
+        assert_eq!(tree(&archive), before);
+        assert!(archive.check().unwrap().is_ok());
+        drop(archive);
+        assert_eq!(buf.get_ref().len() as u64, len);
+
+        let archive = Pk2::open_in(&mut buf, key).unwrap();
+        assert_eq!(tree(&archive), before);
+        assert!(archive.check().unwrap().is_ok());
+        for i in (0..30).filter(|i| i % 4 != 0 && i % 3 != 2) {
+            let mut read = Vec::new();
+            archive
+                .open_file(format!("/dir{}/file{}", i % 3, i))
+                .unwrap()
+                .read_to_end(&mut read)
+                .unwrap();
+            assert_eq!(read, vec![i as u8; 500 + i]);
+        }
+    }
+}
+
+#[test]
+fn test_compact_out_of_bounds() {
+    use std::io::Write;
+    let mut buf = io::Cursor::new(Vec::new());
+    let mut archive = Pk2::create_new_in(&mut buf, "").unwrap();
+    archive
+        .create_file("/a")
+        .unwrap()
+        .write_all(&[1; 100])
+        .unwrap();
+    archive
+        .create_file("/b")
+        .unwrap()
+        .write_all(&[2; 100])
+        .unwrap();
+    archive.delete_file("/a").unwrap();
+    drop(archive);
+    patch_entry(buf.get_mut(), 2, "b", 106, &(1u64 << 40).to_le_bytes());
+    let before = buf.get_ref().clone();
+    let mut archive = Pk2::open_in(&mut buf, "").unwrap();
+    assert!(matches!(archive.compact(), Err(Error::CorruptedFile)));
+    drop(archive);
+    assert_eq!(buf.get_ref(), &before);
+}
+
+#[test]
+fn test_lazy_unreadable_directory() {
+    use std::io::Write;
+    let mut buf = io::Cursor::new(Vec::new());
+    let mut archive = Pk2::create_new_in(&mut buf, "").unwrap();
+    for path in &["/a/x", "/b/y"] {
+        archive
+            .create_file(path)
+            .unwrap()
+            .write_all(b"data")
+            .unwrap();
+    }
+    drop(archive);
+    patch_entry(buf.get_mut(), 1, "a", 106, &(1u64 << 40).to_le_bytes());
+
+    let archive = OpenOptions::new().lazy(true).open_in(&mut buf, "").unwrap();
+    let root = archive.open_directory("/").unwrap();
+    let dir = match root.entries().unwrap().find(|entry| entry.name() == "a") {
+        Some(DirEntry::Directory(dir)) => dir,
+        _ => panic!("/a is missing"),
+    };
+    assert!(dir.entries().is_err());
+    assert!(dir.files().is_err());
+    assert!(archive.open_directory("/a").is_err());
+    assert!(archive.walk("/").unwrap().any(|entry| entry.is_err()));
+    assert!(archive.for_each_file("/", |_, _| Ok(())).is_err());
+    assert!(archive.glob("/a/*").unwrap().any(|entry| entry.is_err()));
+}
+
+#[test]
+fn test_lazy_matches_eager() {
+    use std::io::{Read, Write};
+    use std::path::PathBuf;
+    fn contents<B: io::Read + io::Seek>(archive: &Pk2<B>) -> Vec<(PathBuf, Vec<u8>)> {
+        let mut files = Vec::new();
+        archive
+            .for_each_file("/", |path, mut file| {
+                let mut data = Vec::new();
+                file.read_to_end(&mut data)?;
+                files.push((path.to_owned(), data));
+                Ok(())
+            })
+            .unwrap();
+        files.sort();
+        files
+    }
+
+    let mut base = io::Cursor::new(Vec::new());
+    let mut archive = Pk2::create_new_in(&mut base, "").unwrap();
+    for path in &["/a/b/c/d/deep", "/a/b/file", "/x/y/1", "/x/2", "/broken"] {
+        archive
+            .create_file(path)
+            .unwrap()
+            .write_all(path.as_bytes())
+            .unwrap();
+    }
+    drop(archive);
+    patch_entry(
+        base.get_mut(),
+        2,
+        "broken",
+        106,
+        &(1u64 << 40).to_le_bytes(),
+    );
+
+    let mut results = Vec::new();
+    for &lazy in &[false, true] {
+        let mut buf = base.clone();
+        let mut archive = OpenOptions::new().lazy(lazy).open_in(&mut buf, "").unwrap();
+        let mut deep = Vec::new();
+        archive
+            .open_file("/A/b/C/d/deep")
+            .unwrap()
+            .read_to_end(&mut deep)
+            .unwrap();
+        assert_eq!(deep, b"/a/b/c/d/deep");
+        archive
+            .create_file("/a/b/c/new")
+            .unwrap()
+            .write_all(b"new")
+            .unwrap();
+        archive.create_directory("/a/b/c/d/e").unwrap();
+        archive.delete_directory_all("/x").unwrap();
+        let log = archive.repair().unwrap();
+        assert!(matches!(
+            &log.repairs[..],
+            [repair::Repair::DroppedFile { .. }]
+        ));
+        archive.rekey("169841").unwrap();
+        archive.compact().unwrap();
+        assert!(archive.check().unwrap().is_ok());
+        assert!(archive.open_directory("/a/b/c/d/e").is_ok());
+        let files = contents(&archive);
+        drop(archive);
+
+        let archive = Pk2::open_in(&mut buf, "169841").unwrap();
+        assert_eq!(contents(&archive), files);
+        assert!(archive.check().unwrap().is_ok());
+        results.push(files);
+    }
+    assert_eq!(results[0], results[1]);
+    assert_eq!(results[0].len(), 3);
+}
+
+#[cfg(feature = "euc")]
+#[test]
+fn test_encoding_roundtrip() {
+    use std::io::{Read, Write};
+    let mut buf = io::Cursor::new(Vec::new());
+    let mut options = OpenOptions::new();
+    options.encoding(Encoding::Gbk);
+    let mut archive = options.create_new_in(&mut buf, "").unwrap();
+    archive
+        .create_file("/数据/文件.txt")
+        .unwrap()
+        .write_all(b"data")
+        .unwrap();
+    drop(archive);
+    let encoded = Encoding::Gbk.encode("文件.txt");
+    assert!(buf
+        .get_ref()
+        .windows(encoded.len())
+        .any(|window| window == &encoded[..]));
+
+    let archive = options.open_in(&mut buf, "").unwrap();
+    let mut data = Vec::new();
+    archive
+        .open_file("/数据/文件.txt")
+        .unwrap()
+        .read_to_end(&mut data)
+        .unwrap();
+    assert_eq!(data, b"data");
+    let names: Vec<_> = archive
+        .walk("/")
+        .unwrap()
+        .map(|entry| entry.unwrap().0.to_str().unwrap().to_owned())
+        .collect();
+    assert_eq!(names, ["/数据", "/数据/文件.txt"]);
+    drop(archive);
+
+    // the names don't survive being decoded with another encoding
+    let archive = Pk2::open_in(&mut buf, "").unwrap();
+    assert!(archive.open_file("/数据/文件.txt").is_err());
+}
+
+#[cfg(not(feature = "euc"))]
+#[test]
+fn test_unsupported_encoding() {
+    let mut options = OpenOptions::new();
+    options.encoding(Encoding::Gbk);
+    assert!(matches!(
+        options.create_new_in(io::Cursor::new(Vec::new()), ""),
+        Err(Error::UnsupportedEncoding)
+    ));
+}
+
+#[test]
+fn test_detect_key() {
+    use crate::constants::PK2_KNOWN_KEYS;
+    let keys = || PK2_KNOWN_KEYS.iter().map(|&(_, key)| key);
+    let options = OpenOptions::new();
+    // every key is listed once
+    for (i, key) in keys().enumerate() {
+        assert!(keys().skip(i + 1).all(|other| other != key));
+    }
+    let csro = b"2009\xC4\xEA";
+    let mut buf = io::Cursor::new(Vec::new());
+    Pk2::create_new_in(&mut buf, &csro[..]).unwrap();
+    assert_eq!(
+        Pk2::detect_key(&mut buf, keys(), &options).unwrap(),
+        Some(&csro[..])
+    );
+
+    let mut buf = io::Cursor::new(Vec::new());
+    Pk2::create_new_in(&mut buf, "unknown").unwrap();
+    assert_eq!(Pk2::detect_key(&mut buf, keys(), &options).unwrap(), None);
+    let extra: &[u8] = b"unknown";
+    assert_eq!(
+        Pk2::detect_key(&mut buf, keys().chain(Some(extra)), &options).unwrap(),
+        Some(extra)
+    );
+
+    let mut buf = io::Cursor::new(Vec::new());
+    Pk2::create_new_in(&mut buf, "").unwrap();
+    assert_eq!(
+        Pk2::detect_key(&mut buf, keys(), &options).unwrap(),
+        Some(&[][..])
+    );
+
+    // the header is validated according to the options
+    let mut custom = OpenOptions::new();
+    custom.signature("Custom File Manager");
+    let mut buf = io::Cursor::new(Vec::new());
+    custom.create_new_in(&mut buf, "169841").unwrap();
+    assert!(Pk2::detect_key(&mut buf, keys(), &options).is_err());
+    assert_eq!(
+        Pk2::detect_key(&mut buf, keys(), &custom).unwrap(),
+        Some(&b"169841"[..])
+    );
+}
+
+#[test]
+fn test_open_detect_key() {
+    let path = std::env::temp_dir().join(format!("pk2_detect_key_{}.pk2", std::process::id()));
+    for (key, detected) in &[
+        ("", Some("")),
+        ("169841", Some("169841")),
+        ("unknown", None),
+    ] {
+        let _ = stdfs::remove_file(&path);
+        Pk2::create_new(&path, key).unwrap();
+        match Pk2::open_detect_key(&path) {
+            Ok((_, found)) => assert_eq!(Some(found), detected.map(str::as_bytes)),
+            Err(Error::InvalidKey) => assert!(detected.is_none()),
+            Err(e) => panic!("{}", e),
+        }
+    }
+    stdfs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,472 @@
+//! Building complete archives in one pass.
+use std::collections::btree_map::{BTreeMap, Entry};
+use std::convert::TryFrom;
+use std::fs as stdfs;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path};
+use std::time::SystemTime;
+
+use super::{check_root, OpenOptions, Pk2, Truncate};
+use crate::constants::{
+    PK2_CURRENT_DIR_IDENT, PK2_FILE_BLOCK_ENTRY_COUNT, PK2_FILE_BLOCK_SIZE, PK2_PARENT_DIR_IDENT,
+    PK2_ROOT_BLOCK,
+};
+use crate::error::{Error, Pk2Result};
+use crate::io::RawIo;
+use crate::raw::block_chain::PackBlock;
+use crate::raw::entry::PackEntry;
+use crate::raw::header::PackHeader;
+use crate::raw::{BlockOffset, ChainIndex};
+use crate::{Blowfish, Key, FILETIME};
+
+/// The access, creation and modification times of an entry.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct FileTimes {
+    pub(crate) access_time: FILETIME,
+    pub(crate) create_time: FILETIME,
+    pub(crate) modify_time: FILETIME,
+}
+
+impl FileTimes {
+    /// Creates times that are all set to `time`.
+    pub fn new(time: SystemTime) -> Self {
+        Self::from_filetime(time.into())
+    }
+
+    pub(crate) fn from_filetime(time: FILETIME) -> Self {
+        FileTimes {
+            access_time: time,
+            create_time: time,
+            modify_time: time,
+        }
+    }
+
+    /// Creates times that are all set to the current time.
+    pub fn now() -> Self {
+        Self::new(SystemTime::now())
+    }
+
+    pub fn access_time(&self) -> Option<SystemTime> {
+        self.access_time.into_systime()
+    }
+
+    pub fn create_time(&self) -> Option<SystemTime> {
+        self.create_time.into_systime()
+    }
+
+    pub fn modify_time(&self) -> Option<SystemTime> {
+        self.modify_time.into_systime()
+    }
+
+    pub fn set_access_time(&mut self, time: SystemTime) {
+        self.access_time = time.into();
+    }
+
+    pub fn set_create_time(&mut self, time: SystemTime) {
+        self.create_time = time.into();
+    }
+
+    pub fn set_modify_time(&mut self, time: SystemTime) {
+        self.modify_time = time.into();
+    }
+
+    pub(crate) fn apply(&self, entry: &mut PackEntry) {
+        match entry {
+            PackEntry::Directory(dir) => {
+                dir.access_time = self.access_time;
+                dir.create_time = self.create_time;
+                dir.modify_time = self.modify_time;
+            }
+            PackEntry::File(file) => {
+                file.access_time = self.access_time;
+                file.create_time = self.create_time;
+                file.modify_time = self.modify_time;
+            }
+            PackEntry::Empty(_) => (),
+        }
+    }
+}
+
+/// Builds a new archive from a complete tree of files in one pass.
+///
+/// Unlike creating the files one by one through [`Pk2::create_file`], which
+/// interleaves file data and newly allocated blocks, the builder knows the
+/// size of every directory up front. It places all block chains contiguously
+/// right after the header and writes the file data behind them, sorted by
+/// path, so that the resulting archive has no gaps and its index can be read
+/// in one go.
+///
+/// Entries are sorted by their ASCII lowercased names, names that only
+/// differ in case are rejected as the archive can't tell them apart. As the
+/// order in which entries are added doesn't matter and all unused bytes are
+/// zeroed, building the same tree with the same times twice produces
+/// identical archives. Directories that are created implicitly, including
+/// the root unless added explicitly, use the
+/// [`OpenOptions::fixed_time`] if set and the current time otherwise.
+pub struct Pk2Builder<'a> {
+    options: OpenOptions,
+    root: DirNode<'a>,
+}
+
+struct DirNode<'a> {
+    times: FileTimes,
+    // ascii lowercased name -> (name, node)
+    children: BTreeMap<String, (String, Node<'a>)>,
+}
+
+enum Node<'a> {
+    Directory(DirNode<'a>),
+    File(FileNode<'a>),
+}
+
+struct FileNode<'a> {
+    times: FileTimes,
+    source: Box<dyn Read + 'a>,
+}
+
+impl<'a> DirNode<'a> {
+    fn new(times: FileTimes) -> Self {
+        DirNode {
+            times,
+            children: BTreeMap::new(),
+        }
+    }
+
+    /// The number of blocks the chain of this directory takes up.
+    fn block_count(&self, is_root: bool) -> usize {
+        // the root only has a current directory link
+        let links = if is_root { 1 } else { 2 };
+        let entries = links + self.children.len();
+        entries.div_ceil(PK2_FILE_BLOCK_ENTRY_COUNT)
+    }
+}
+
+impl Default for Pk2Builder<'_> {
+    fn default() -> Self {
+        Self::with_options(&OpenOptions::default())
+    }
+}
+
+impl<'a> Pk2Builder<'a> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates a builder using the encoding, signature and version of
+    /// `options`. The options are also used to open the built archive.
+    pub fn with_options(options: &OpenOptions) -> Self {
+        Pk2Builder {
+            options: options.clone(),
+            root: DirNode::new(options.default_times()),
+        }
+    }
+
+    /// Adds a file at the given path whose contents are read from `source`
+    /// when the archive gets built. Missing parent directories are created.
+    pub fn add_file<P: AsRef<Path>, R: Read + 'a>(
+        &mut self,
+        path: P,
+        source: R,
+        times: FileTimes,
+    ) -> Pk2Result<()> {
+        let (parent, name) = self.parent_and_name(path.as_ref())?;
+        let name = name.ok_or(Error::InvalidPath)?;
+        match parent.children.entry(name.to_ascii_lowercase()) {
+            Entry::Occupied(_) => Err(Error::AlreadyExists),
+            Entry::Vacant(entry) => {
+                let source = Box::new(source);
+                entry.insert((name.to_owned(), Node::File(FileNode { times, source })));
+                Ok(())
+            }
+        }
+    }
+
+    /// Adds a directory at the given path, which is only required for empty
+    /// directories or to set the times of a directory. Missing parent
+    /// directories are created, if the directory exists already only its
+    /// times are updated.
+    pub fn add_directory<P: AsRef<Path>>(&mut self, path: P, times: FileTimes) -> Pk2Result<()> {
+        let (parent, name) = self.parent_and_name(path.as_ref())?;
+        let name = match name {
+            Some(name) => name,
+            None => {
+                parent.times = times;
+                return Ok(());
+            }
+        };
+        let (_, node) = parent
+            .children
+            .entry(name.to_ascii_lowercase())
+            .or_insert_with(|| (name.to_owned(), Node::Directory(DirNode::new(times))));
+        match node {
+            Node::Directory(dir) => {
+                dir.times = times;
+                Ok(())
+            }
+            Node::File(_) => Err(Error::AlreadyExists),
+        }
+    }
+
+    /// Resolves the parent directory of `path`, creating missing directories,
+    /// and returns it together with the last component of `path`, which is
+    /// `None` for the root.
+    fn parent_and_name<'p>(
+        &mut self,
+        path: &'p Path,
+    ) -> Pk2Result<(&mut DirNode<'a>, Option<&'p str>)> {
+        let encoding = self.options.encoding;
+        let default_times = self.options.default_times();
+        let mut names = check_root(path)?
+            .components()
+            .map(|component| match component {
+                Component::Normal(name) => {
+                    let name = name.to_str().ok_or(Error::NonUnicodePath)?;
+                    PackEntry::validate_name(name, encoding)?;
+                    Ok(name)
+                }
+                _ => Err(Error::InvalidPath),
+            })
+            .collect::<Pk2Result<Vec<_>>>()?;
+        let name = names.pop();
+        let mut dir = &mut self.root;
+        for name in names {
+            let (_, node) = dir
+                .children
+                .entry(name.to_ascii_lowercase())
+                .or_insert_with(|| {
+                    (
+                        name.to_owned(),
+                        Node::Directory(DirNode::new(default_times)),
+                    )
+                });
+            dir = match node {
+                Node::Directory(dir) => dir,
+                Node::File(_) => return Err(Error::ExpectedDirectory),
+            };
+        }
+        Ok((dir, name))
+    }
+
+    /// Builds the archive at the given path, failing if it already exists.
+    /// The archive is not journaled while being built.
+    pub fn build<P: AsRef<Path>, K: AsRef<[u8]>>(self, path: P, key: K) -> Pk2Result<Pk2> {
+        self.build_with_key(path, Key::from(key.as_ref()))
+    }
+
+    /// Like [`Pk2Builder::build`], but takes a [`Key`] which allows for custom
+    /// salts and raw keys.
+    pub fn build_with_key<P: AsRef<Path>>(self, path: P, key: Key<'_>) -> Pk2Result<Pk2> {
+        self.options.validate()?;
+        let file = stdfs::OpenOptions::new()
+            .create_new(true)
+            .write(true)
+            .read(true)
+            .open(path)?;
+        self.build_in_with_key(file, key)
+    }
+
+    /// Builds the archive into `file`, overwriting and truncating any previous
+    /// contents, and opens it.
+    pub fn build_in<B, K>(self, file: B, key: K) -> Pk2Result<Pk2<B>>
+    where
+        B: Read + Write + Seek + Truncate,
+        K: AsRef<[u8]>,
+    {
+        self.build_in_with_key(file, Key::from(key.as_ref()))
+    }
+
+    /// Like [`Pk2Builder::build_in`], but takes a [`Key`] which allows for
+    /// custom salts and raw keys.
+    pub fn build_in_with_key<B>(self, mut file: B, key: Key<'_>) -> Pk2Result<Pk2<B>>
+    where
+        B: Read + Write + Seek + Truncate,
+    {
+        self.options.validate()?;
+        let blowfish = key.blowfish()?;
+        let mut header = match &blowfish {
+            Some(bf) => PackHeader::new_encrypted(bf),
+            None => PackHeader::default(),
+        };
+        header.signature = self.options.signature;
+        header.version = self.options.version;
+        file.seek(SeekFrom::Start(0))?;
+        header.to_writer(&mut file)?;
+
+        let mut chain_offsets = Vec::new();
+        let mut index_end = PK2_ROOT_BLOCK.0;
+        Self::layout_chains(&self.root, true, &mut index_end, &mut chain_offsets);
+        let mut writer = ArchiveWriter {
+            blowfish: blowfish.as_ref(),
+            options: &self.options,
+            file: &mut file,
+            chain_offsets: chain_offsets.into_iter(),
+            data_end: index_end,
+        };
+        writer.write_directory(self.root, None)?;
+        let len = writer.data_end;
+        file.flush()?;
+        file.truncate(len)?;
+        self.options.open_in_with_key(file, key)
+    }
+
+    /// Assigns the offsets of the chains of `dir` and its subdirectories in
+    /// depth-first order, directly following each other.
+    fn layout_chains(dir: &DirNode<'_>, is_root: bool, end: &mut u64, offsets: &mut Vec<u64>) {
+        offsets.push(*end);
+        *end += (dir.block_count(is_root) * PK2_FILE_BLOCK_SIZE) as u64;
+        for (_, node) in dir.children.values() {
+            if let Node::Directory(child) = node {
+                Self::layout_chains(child, false, end, offsets);
+            }
+        }
+    }
+}
+
+struct ArchiveWriter<'w, B> {
+    blowfish: Option<&'w Blowfish>,
+    options: &'w OpenOptions,
+    file: &'w mut B,
+    // chain offsets in the order the directories are visited
+    chain_offsets: std::vec::IntoIter<u64>,
+    data_end: u64,
+}
+
+impl<B: Write + Seek> ArchiveWriter<'_, B> {
+    /// Writes the data of all files below `dir` as well as the chains of it
+    /// and its subdirectories, visiting them in the same order as
+    /// [`Pk2Builder::layout_chains`]. Returns the chain index of `dir`.
+    fn write_directory(
+        &mut self,
+        dir: DirNode<'_>,
+        parent: Option<(ChainIndex, FileTimes)>,
+    ) -> Pk2Result<ChainIndex> {
+        let chain = ChainIndex(self.chain_offsets.next().unwrap());
+        let block_count = dir.block_count(parent.is_none());
+        let mut entries = Vec::with_capacity(block_count * PK2_FILE_BLOCK_ENTRY_COUNT);
+        let mut current = PackEntry::new_directory(PK2_CURRENT_DIR_IDENT, chain, None);
+        dir.times.apply(&mut current);
+        entries.push(current);
+        if let Some((parent, parent_times)) = parent {
+            let mut parent_link = PackEntry::new_directory(PK2_PARENT_DIR_IDENT, parent, None);
+            parent_times.apply(&mut parent_link);
+            entries.push(parent_link);
+        }
+        let times = dir.times;
+        for (_, (name, node)) in dir.children {
+            let entry = match node {
+                Node::Directory(child) => {
+                    let child_times = child.times;
+                    let child_chain = self.write_directory(child, Some((chain, times)))?;
+                    let mut entry = PackEntry::new_directory(name, child_chain, None);
+                    child_times.apply(&mut entry);
+                    entry
+                }
+                Node::File(file) => {
+                    let (pos_data, size) = self.write_data(file.source)?;
+                    let mut entry = PackEntry::new_file(name, pos_data, size, None);
+                    file.times.apply(&mut entry);
+                    entry
+                }
+            };
+            entries.push(entry);
+        }
+        let mut entries = entries.into_iter();
+        for idx in 0..block_count {
+            let mut block = PackBlock::default();
+            for (slot, entry) in (0..PK2_FILE_BLOCK_ENTRY_COUNT).zip(&mut entries) {
+                block[slot] = entry;
+            }
+            if idx + 1 < block_count {
+                let next_block = chain.0 + ((idx + 1) * PK2_FILE_BLOCK_SIZE) as u64;
+                block[PK2_FILE_BLOCK_ENTRY_COUNT - 1].set_next_block(BlockOffset(next_block));
+            }
+            let offset = BlockOffset(chain.0 + (idx * PK2_FILE_BLOCK_SIZE) as u64);
+            crate::io::write_block(
+                self.blowfish,
+                self.options.encoding,
+                &mut *self.file,
+                offset,
+                &block,
+            )?;
+        }
+        Ok(chain)
+    }
+
+    /// Appends the contents of `source` to the data section, returning their
+    /// position and size.
+    fn write_data(&mut self, mut source: Box<dyn Read + '_>) -> Pk2Result<(u64, u32)> {
+        self.file.seek(SeekFrom::Start(self.data_end))?;
+        let size = io::copy(&mut source, &mut *self.file)?;
+        let size = u32::try_from(size).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                "file is too large to be stored in an archive",
+            )
+        })?;
+        if size == 0 {
+            return Ok((0, 0));
+        }
+        let pos_data = self.data_end;
+        self.data_end += u64::from(size);
+        Ok((pos_data, size))
+    }
+}
+
+#[test]
+fn test_builder_layout() {
+    let mut builder = Pk2Builder::new();
+    for i in 0..30 {
+        let data = format!("file {}", i).into_bytes();
+        builder
+            .add_file(
+                format!("/dir/{}.txt", i),
+                io::Cursor::new(data),
+                FileTimes::now(),
+            )
+            .unwrap();
+    }
+    builder
+        .add_file("/a.txt", &b"root file"[..], FileTimes::now())
+        .unwrap();
+    builder.add_directory("/empty", FileTimes::now()).unwrap();
+    assert!(matches!(
+        builder.add_file("/DIR/0.TXT", &b""[..], FileTimes::now()),
+        Err(Error::AlreadyExists)
+    ));
+    assert!(matches!(
+        builder.add_file("/a.txt/b", &b""[..], FileTimes::now()),
+        Err(Error::ExpectedDirectory)
+    ));
+
+    let mut buf = io::Cursor::new(Vec::new());
+    let archive = builder.build_in(&mut buf, "169841").unwrap();
+    assert!(archive.check().unwrap().is_ok());
+    let mut contents = String::new();
+    archive
+        .open_file("/dir/29.txt")
+        .unwrap()
+        .read_to_string(&mut contents)
+        .unwrap();
+    assert_eq!(contents, "file 29");
+    assert!(archive.open_directory("/empty").is_ok());
+    drop(archive);
+    // root, /dir with two blocks and /empty, followed by the data of /a.txt
+    let index_end = PK2_ROOT_BLOCK.0 as usize + 4 * PK2_FILE_BLOCK_SIZE;
+    assert_eq!(&buf.get_ref()[index_end..][..9], b"root file");
+}
+
+#[test]
+fn test_build_in_truncates() {
+    fn build<B: Read + Write + Seek + Truncate>(file: B) {
+        let times = FileTimes::new(SystemTime::UNIX_EPOCH);
+        let mut builder = Pk2Builder::new();
+        builder.add_directory("/", times).unwrap();
+        builder.add_file("/a.txt", &b"contents"[..], times).unwrap();
+        builder.build_in(file, "169841").unwrap();
+    }
+    let mut fresh = io::Cursor::new(Vec::new());
+    build(&mut fresh);
+    let mut reused = io::Cursor::new(vec![0xAA; 4 * fresh.get_ref().len()]);
+    build(&mut reused);
+    assert_eq!(fresh.get_ref(), reused.get_ref());
+}
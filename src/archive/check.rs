@@ -0,0 +1,406 @@
+//! Integrity checking of archives.
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{Read, Seek};
+use std::path::PathBuf;
+
+use super::Pk2;
+use crate::constants::{PK2_FILE_BLOCK_SIZE, PK2_ROOT_BLOCK};
+use crate::error::{Error, Pk2Result};
+use crate::raw::entry::PackEntry;
+use crate::raw::BlockOffset;
+
+/// The result of [`Pk2::check`], listing every problem found in the archive.
+#[derive(Debug, Default)]
+pub struct CheckReport {
+    pub problems: Vec<Problem>,
+}
+
+impl CheckReport {
+    /// Whether no problems have been found.
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+/// A problem found while checking an archive. Paths refer to the entry the
+/// problem was found at, for blocks this is the directory they belong to.
+#[derive(Debug)]
+pub enum Problem {
+    /// A block of a chain lies partially or completely outside of the archive.
+    BlockOutOfBounds { path: PathBuf, offset: u64 },
+    /// The data of a file lies partially or completely outside of the
+    /// archive.
+    DataOutOfBounds {
+        path: PathBuf,
+        offset: u64,
+        size: u32,
+    },
+    /// Two regions of the archive are in use by different owners at once.
+    Overlap { first: Region, second: Region },
+    /// The `.` entry of a directory doesn't point at the directory itself.
+    InvalidCurrentLink {
+        path: PathBuf,
+        expected: u64,
+        found: u64,
+    },
+    /// The `..` entry of a directory doesn't point at its parent directory.
+    InvalidParentLink {
+        path: PathBuf,
+        expected: u64,
+        found: u64,
+    },
+    /// A directory points at a chain that another directory points at as
+    /// well.
+    SharedChain {
+        path: PathBuf,
+        other: PathBuf,
+        chain: u64,
+    },
+    /// The chain of a directory couldn't be read, for example because its
+    /// blocks link to each other in a cycle.
+    UnreadableChain {
+        path: PathBuf,
+        chain: u64,
+        error: Error,
+    },
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Problem::BlockOutOfBounds { path, offset } => write!(
+                f,
+                "{}: block at {:#x} lies outside of the archive",
+                path.display(),
+                offset
+            ),
+            Problem::DataOutOfBounds { path, offset, size } => write!(
+                f,
+                "{}: data at {:#x} with size {} lies outside of the archive",
+                path.display(),
+                offset,
+                size
+            ),
+            Problem::Overlap { first, second } => write!(f, "{} overlaps {}", first, second),
+            Problem::InvalidCurrentLink {
+                path,
+                expected,
+                found,
+            } => write!(
+                f,
+                "{}: `.` points at {:#x} instead of {:#x}",
+                path.display(),
+                found,
+                expected
+            ),
+            Problem::InvalidParentLink {
+                path,
+                expected,
+                found,
+            } => write!(
+                f,
+                "{}: `..` points at {:#x} instead of {:#x}",
+                path.display(),
+                found,
+                expected
+            ),
+            Problem::SharedChain { path, other, chain } => write!(
+                f,
+                "{}: chain at {:#x} is shared with {}",
+                path.display(),
+                chain,
+                other.display()
+            ),
+            Problem::UnreadableChain { path, chain, error } => write!(
+                f,
+                "{}: chain at {:#x} couldn't be read: {}",
+                path.display(),
+                chain,
+                error
+            ),
+        }
+    }
+}
+
+/// A region of the archive and what it is used for.
+#[derive(Clone, Debug)]
+pub struct Region {
+    pub path: PathBuf,
+    pub kind: RegionKind,
+    pub offset: u64,
+    pub len: u64,
+}
+
+impl fmt::Display for Region {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self.kind {
+            RegionKind::Header => "header",
+            RegionKind::Block => "block",
+            RegionKind::Data => "data",
+        };
+        write!(
+            f,
+            "{} of {} at {:#x}..{:#x}",
+            kind,
+            self.path.display(),
+            self.offset,
+            self.offset + self.len
+        )
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum RegionKind {
+    Header,
+    Block,
+    Data,
+}
+
+impl<B> Pk2<B>
+where
+    B: Read + Seek,
+{
+    /// Checks the whole index of the archive for inconsistencies, reporting
+    /// data or blocks lying outside of the archive, overlapping regions,
+    /// broken `.` and `..` links, chains shared between directories and
+    /// chains that can't be read. Only errors of the underlying file are
+    /// returned as an error.
+    ///
+    /// Chains that can't be read make opening an archive fail unless it is
+    /// opened lazily, so archives that are about to be checked should be
+    /// opened with [`OpenOptions::lazy`](super::OpenOptions::lazy).
+    pub fn check(&self) -> Pk2Result<CheckReport> {
+        let file_len = crate::io::file_len(&mut *self.file.lock())?;
+        let mut report = CheckReport::default();
+        let mut regions = vec![Region {
+            path: PathBuf::from("/"),
+            kind: RegionKind::Header,
+            offset: 0,
+            len: PK2_ROOT_BLOCK.0,
+        }];
+        let mut visited = HashMap::new();
+        visited.insert(PK2_ROOT_BLOCK, PathBuf::from("/"));
+        // (path, chain, parent chain)
+        let mut stack = vec![(PathBuf::from("/"), PK2_ROOT_BLOCK, PK2_ROOT_BLOCK)];
+        while let Some((path, chain_index, parent)) = stack.pop() {
+            let chain = match self.load_chain(chain_index) {
+                Ok(chain) => chain,
+                Err(Error::Io(e)) if !is_eof(&e) => return Err(Error::Io(e)),
+                Err(error) => {
+                    report.problems.push(Problem::UnreadableChain {
+                        path,
+                        chain: chain_index.0,
+                        error,
+                    });
+                    continue;
+                }
+            };
+            for BlockOffset(offset) in chain.block_offsets() {
+                let len = PK2_FILE_BLOCK_SIZE as u64;
+                if offset.saturating_add(len) > file_len {
+                    report.problems.push(Problem::BlockOutOfBounds {
+                        path: path.clone(),
+                        offset,
+                    });
+                } else {
+                    regions.push(Region {
+                        path: path.clone(),
+                        kind: RegionKind::Block,
+                        offset,
+                        len,
+                    });
+                }
+            }
+            for entry in chain.entries() {
+                match entry {
+                    PackEntry::File(file) => {
+                        let file_path = path.join(file.name());
+                        let (offset, size) = (file.pos_data(), file.size());
+                        if offset.saturating_add(size as u64) > file_len {
+                            report.problems.push(Problem::DataOutOfBounds {
+                                path: file_path,
+                                offset,
+                                size,
+                            });
+                        } else if size != 0 {
+                            regions.push(Region {
+                                path: file_path,
+                                kind: RegionKind::Data,
+                                offset,
+                                len: size as u64,
+                            });
+                        }
+                    }
+                    PackEntry::Directory(dir) if dir.is_current_link() => {
+                        let found = dir.children_position();
+                        if found != chain_index {
+                            report.problems.push(Problem::InvalidCurrentLink {
+                                path: path.clone(),
+                                expected: chain_index.0,
+                                found: found.0,
+                            });
+                        }
+                    }
+                    PackEntry::Directory(dir) if dir.is_parent_link() => {
+                        let found = dir.children_position();
+                        if found != parent {
+                            report.problems.push(Problem::InvalidParentLink {
+                                path: path.clone(),
+                                expected: parent.0,
+                                found: found.0,
+                            });
+                        }
+                    }
+                    PackEntry::Directory(dir) => {
+                        let dir_path = path.join(dir.name());
+                        let children = dir.children_position();
+                        match visited.get(&children) {
+                            Some(other) => report.problems.push(Problem::SharedChain {
+                                path: dir_path,
+                                other: other.clone(),
+                                chain: children.0,
+                            }),
+                            None => {
+                                visited.insert(children, dir_path.clone());
+                                stack.push((dir_path, children, chain_index));
+                            }
+                        }
+                    }
+                    PackEntry::Empty(_) => (),
+                }
+            }
+        }
+
+        regions.sort_by_key(|region| region.offset);
+        // the region reaching the furthest into the file seen so far
+        let mut furthest: Option<&Region> = None;
+        for region in &regions {
+            if let Some(prev) = furthest {
+                if region.offset < prev.offset + prev.len {
+                    report.problems.push(Problem::Overlap {
+                        first: prev.clone(),
+                        second: region.clone(),
+                    });
+                }
+                if region.offset + region.len <= prev.offset + prev.len {
+                    continue;
+                }
+            }
+            furthest = Some(region);
+        }
+        Ok(report)
+    }
+}
+
+// chains pointing past the end of the archive fail to read with an unexpected eof
+fn is_eof(e: &std::io::Error) -> bool {
+    e.kind() == std::io::ErrorKind::UnexpectedEof
+}
+
+#[test]
+fn test_check_block_out_of_bounds() {
+    // the archive shrinks after the chain has been loaded
+    let mut buf = std::io::Cursor::new(Vec::new());
+    let mut archive = Pk2::create_new_in(&mut buf, "").unwrap();
+    archive.create_file("/file").unwrap();
+    archive.create_directory("/dir").unwrap();
+    let len = crate::io::file_len(archive.file.get_mut()).unwrap();
+    super::Truncate::truncate(&mut archive.file.get_mut(), len - 100).unwrap();
+    let problems = archive.check().unwrap().problems;
+    assert!(matches!(
+        &problems[..],
+        [Problem::BlockOutOfBounds { path, .. }] if path.ends_with("dir")
+    ));
+}
+
+/// The paths of a damaged archive, the damage and whether the problems found
+/// are the expected ones.
+#[cfg(test)]
+type CheckCase = (
+    &'static [&'static str],
+    super::Damage,
+    fn(&[Problem]) -> bool,
+);
+
+#[test]
+fn test_check_problems() {
+    let cases: &[CheckCase] = &[
+        (
+            &["/dir/file"],
+            |_, buf| super::patch_entry(buf, 2, "file", 106, &(1u64 << 40).to_le_bytes()),
+            |problems| matches!(problems, [Problem::DataOutOfBounds { size: 100, .. }]),
+        ),
+        (
+            &["/a", "/b"],
+            |_, buf| super::patch_entry(buf, 2, "b", 106, &0u64.to_le_bytes()),
+            |problems| {
+                matches!(
+                    problems,
+                    [Problem::Overlap { first, second }]
+                        if first.kind == RegionKind::Header && second.kind == RegionKind::Data
+                )
+            },
+        ),
+        (
+            &["/file"],
+            |_, buf| super::patch_entry(buf, 1, ".", 106, &0x1234u64.to_le_bytes()),
+            |problems| {
+                matches!(
+                    problems,
+                    [Problem::InvalidCurrentLink { found: 0x1234, .. }]
+                )
+            },
+        ),
+        (
+            &["/dir/file"],
+            |_, buf| super::patch_entry(buf, 1, "..", 106, &0x1234u64.to_le_bytes()),
+            |problems| {
+                matches!(
+                    problems,
+                    [Problem::InvalidParentLink { path, expected, found: 0x1234 }]
+                        if path.ends_with("dir") && *expected == PK2_ROOT_BLOCK.0
+                )
+            },
+        ),
+        (
+            &["/a/file", "/b/"],
+            |archive, buf| {
+                let chain = super::chain_of(archive, "/a");
+                super::patch_entry(buf, 1, "b", 106, &chain.to_le_bytes())
+            },
+            |problems| matches!(problems, [Problem::SharedChain { .. }]),
+        ),
+        (
+            &["/a/file"],
+            |_, buf| super::patch_entry(buf, 1, "a", 106, &(1u64 << 40).to_le_bytes()),
+            |problems| {
+                matches!(
+                    problems,
+                    [Problem::UnreadableChain { path, chain, .. }]
+                        if path.ends_with("a") && *chain == 1 << 40
+                )
+            },
+        ),
+        (
+            // blocks linking back to the start of their chain
+            &["/a/file"],
+            |archive, buf| {
+                let chain = super::chain_of(archive, "/a");
+                let last = chain as usize + 19 * 128;
+                buf[last + 118..][..8].copy_from_slice(&chain.to_le_bytes());
+            },
+            |problems| matches!(problems, [Problem::UnreadableChain { .. }]),
+        ),
+    ];
+    for (i, &(paths, damage, expected)) in cases.iter().enumerate() {
+        let mut buf = super::damaged_archive(paths, damage);
+        // unreadable chains only show up when opening lazily
+        let archive = super::OpenOptions::new()
+            .lazy(true)
+            .open_in(&mut buf, "")
+            .unwrap();
+        let problems = archive.check().unwrap().problems;
+        assert!(expected(&problems), "case {}: {:?}", i, problems);
+    }
+}
@@ -1,9 +1,12 @@
 #![allow(clippy::match_ref_pats)]
+use std::collections::{HashSet, VecDeque};
 use std::io::{self, Read, Seek, SeekFrom, Write};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::time::SystemTime;
 
-use crate::archive::Pk2;
+pub use crate::archive::glob::Glob;
+use crate::archive::glob::Pattern;
+use crate::archive::{FileTimes, Pk2};
 use crate::error::{Error, Pk2Result};
 use crate::raw::block_chain::PackBlockChain;
 use crate::raw::entry::{DirectoryEntry, FileEntry, PackEntry};
@@ -40,6 +43,16 @@ impl<'pk2, B> File<'pk2, B> {
         self.entry().create_time()
     }
 
+    /// Returns all times of this file, as used by [`Pk2Builder`](super::Pk2Builder).
+    pub fn times(&self) -> FileTimes {
+        let entry = self.entry();
+        FileTimes {
+            access_time: entry.access_time,
+            create_time: entry.create_time,
+            modify_time: entry.modify_time,
+        }
+    }
+
     #[inline]
     pub fn name(&self) -> &str {
         self.entry().name()
@@ -54,6 +67,23 @@ impl<'pk2, B> File<'pk2, B> {
     }
 }
 
+#[cfg(feature = "mmap")]
+impl File<'_, super::mmap::MappedFile> {
+    /// Returns the contents of this file without copying them out of the
+    /// memory map.
+    pub fn as_bytes(&self) -> Pk2Result<super::mmap::MappedBytes> {
+        let entry = self.entry();
+        self.archive
+            .file
+            .map
+            .as_ref()
+            .and_then(|map| {
+                super::mmap::MappedBytes::new(map, entry.pos_data(), entry.size() as u64)
+            })
+            .ok_or(Error::CorruptedFile)
+    }
+}
+
 impl<B> Seek for File<'_, B> {
     fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
         let size = self.entry().size() as u64;
@@ -85,8 +115,26 @@ where
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         let pos_data = self.entry().pos_data();
         let size = self.entry().size();
+        // memory mapped archives are read directly without taking the lock
+        #[cfg(feature = "mmap")]
+        {
+            if let Some(map) = self.archive.file.mapped() {
+                let data = (pos_data as usize)
+                    .checked_add(self.seek_pos as usize)
+                    .and_then(|start| {
+                        map.get(start..(pos_data as usize).checked_add(size as usize)?)
+                    })
+                    .ok_or_else(|| {
+                        io::Error::new(io::ErrorKind::UnexpectedEof, "file data is out of bounds")
+                    })?;
+                let n = buf.len().min(data.len());
+                buf[..n].copy_from_slice(&data[..n]);
+                self.seek_pos += n as u64;
+                return Ok(n);
+            }
+        }
         let n = {
-            let mut file = self.archive.file.borrow_mut();
+            let mut file = self.archive.file.lock();
             file.seek(SeekFrom::Start(pos_data + self.seek_pos as u64))?;
             let len = buf.len().min((size as u64 - self.seek_pos) as usize);
             file.read(&mut buf[..len])?
@@ -187,7 +235,7 @@ where
         let pos_data = self.entry().pos_data();
         let size = self.entry().size();
         self.data.resize(size as usize, 0);
-        let mut file = self.archive.file.borrow_mut();
+        let mut file = self.archive.file.get_mut();
         file.seek(SeekFrom::Start(pos_data as u64))?;
         file.read_exact(&mut self.data)?;
         Ok(())
@@ -281,6 +329,7 @@ where
 
     fn flush(&mut self) -> io::Result<()> {
         if !self.data.is_empty() {
+            self.archive.file.begin()?;
             let (file_data_pos, file_data_size) = {
                 let entry_index = self.entry_index;
                 // cant use `entry_mut` since this would borrow self for the whole scope
@@ -295,37 +344,42 @@ where
                     None => panic!("invalid file object, this is a bug"),
                 }
             };
-            // new unwritten file/more data than what fits, so use a new block
+            let free_list = &mut self.archive.free_list;
+            // new unwritten file/more data than what fits, so release the old region and
+            // allocate a new one
             if self.data.len() > *file_data_size as usize {
+                free_list.free(*file_data_pos, *file_data_size as u64);
                 *file_data_pos = crate::io::write_new_data_buffer(
-                    &mut *self.archive.file.borrow_mut(),
+                    free_list,
+                    self.archive.file.get_mut(),
                     &self.data,
                 )?;
                 *file_data_size = self.data.len() as u32;
-            // we got data to write that is not bigger than the block we have
+            // we got data to write that is not bigger than the block we have, release
+            // whatever we no longer need of it
             } else {
                 crate::io::write_data_buffer_at(
-                    &mut *self.archive.file.borrow_mut(),
+                    self.archive.file.get_mut(),
                     *file_data_pos,
                     &self.data,
                 )?;
+                free_list.free(
+                    *file_data_pos + self.data.len() as u64,
+                    (*file_data_size as usize - self.data.len()) as u64,
+                );
                 *file_data_size = self.data.len() as u32;
             }
             // update entry
-            let entry_offset = self
-                .archive
-                .get_chain(self.chain)
-                .and_then(|chain| chain.file_offset_for_entry(self.entry_index))
-                .unwrap();
-            self.set_modify_time(SystemTime::now());
-            crate::io::write_entry_at(
+            let now = self.archive.now();
+            self.entry_mut().modify_time = now;
+            crate::io::write_chain_entry(
                 self.archive.blowfish.as_ref(),
-                &mut *self.archive.file.borrow_mut(),
-                entry_offset,
-                self.archive
-                    .get_entry(self.chain, self.entry_index)
-                    .unwrap(),
-            )
+                self.archive.encoding,
+                self.archive.file.get_mut(),
+                self.archive.block_manager.get(self.chain).unwrap(),
+                self.entry_index,
+            )?;
+            self.archive.file.commit()
         } else {
             Ok(())
         }
@@ -341,12 +395,137 @@ where
     }
 }
 
+/// A file that writes its data straight into the archive instead of buffering
+/// it, see [`Pk2::create_file_streaming`].
+///
+/// Dropping the stream flushes it but discards any error that occurs while
+/// doing so, call [`Write::flush`] before dropping it to handle them.
+pub struct FileStream<'pk2, B = std::fs::File>
+where
+    B: Read + Write + Seek,
+{
+    archive: &'pk2 mut Pk2<B>,
+    // the chain this file resides in
+    chain: ChainIndex,
+    // the index of this file in the chain
+    entry_index: usize,
+    // the amount of bytes written so far
+    written: u64,
+}
+
+impl<'pk2, B> FileStream<'pk2, B>
+where
+    B: Read + Write + Seek,
+{
+    pub(super) fn new(archive: &'pk2 mut Pk2<B>, chain: ChainIndex, entry_index: usize) -> Self {
+        FileStream {
+            archive,
+            chain,
+            entry_index,
+            written: 0,
+        }
+    }
+
+    pub fn modify_time(&self) -> Option<SystemTime> {
+        self.entry().modify_time.into_systime()
+    }
+
+    pub fn access_time(&self) -> Option<SystemTime> {
+        self.entry().access_time.into_systime()
+    }
+
+    pub fn create_time(&self) -> Option<SystemTime> {
+        self.entry().create_time.into_systime()
+    }
+
+    pub fn set_access_time(&mut self, time: SystemTime) {
+        self.entry_mut().access_time = time.into();
+    }
+
+    pub fn set_create_time(&mut self, time: SystemTime) {
+        self.entry_mut().create_time = time.into();
+    }
+
+    #[inline]
+    pub fn name(&self) -> &str {
+        self.entry().name()
+    }
+
+    #[inline]
+    fn entry(&self) -> &FileEntry {
+        self.archive
+            .get_entry(self.chain, self.entry_index)
+            .and_then(PackEntry::as_file)
+            .expect("invalid file object, this is a bug")
+    }
+
+    #[inline]
+    fn entry_mut(&mut self) -> &mut FileEntry {
+        self.archive
+            .get_entry_mut(self.chain, self.entry_index)
+            .and_then(PackEntry::as_file_mut)
+            .expect("invalid file object, this is a bug")
+    }
+}
+
+impl<B> Write for FileStream<'_, B>
+where
+    B: Read + Write + Seek,
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.written + buf.len() as u64 > u32::MAX as u64 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "file size exceeds the maximum size of an archive file",
+            ));
+        }
+        let pos_data = self.entry().pos_data();
+        let mut file = self.archive.file.get_mut();
+        file.seek(SeekFrom::Start(pos_data + self.written))?;
+        let n = file.write(buf)?;
+        self.written += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let written = self.written as u32;
+        let now = self.archive.now();
+        let entry = self.entry_mut();
+        entry.size = written;
+        entry.modify_time = now;
+        crate::io::write_chain_entry(
+            self.archive.blowfish.as_ref(),
+            self.archive.encoding,
+            self.archive.file.get_mut(),
+            self.archive.block_manager.get(self.chain).unwrap(),
+            self.entry_index,
+        )?;
+        self.archive.file.commit()
+    }
+}
+
+impl<B> Drop for FileStream<'_, B>
+where
+    B: Write + Read + Seek,
+{
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
 pub enum DirEntry<'pk2, B> {
     Directory(Directory<'pk2, B>),
     File(File<'pk2, B>),
 }
 
 impl<'pk2, B> DirEntry<'pk2, B> {
+    pub fn name(&self) -> &str {
+        match self {
+            DirEntry::Directory(dir) => dir.name(),
+            DirEntry::File(file) => file.name(),
+        }
+    }
+
     fn from(
         entry: &PackEntry,
         archive: &'pk2 Pk2<B>,
@@ -390,14 +569,6 @@ impl<'pk2, B> Directory<'pk2, B> {
             .expect("invalid file object, this is a bug")
     }
 
-    // returns the chain this folder represents
-    #[inline]
-    fn dir_chain(&self, chain: ChainIndex) -> &'pk2 PackBlockChain {
-        self.archive
-            .get_chain(chain)
-            .expect("invalid dir object, this is a bug")
-    }
-
     pub fn name(&self) -> &str {
         self.entry().name()
     }
@@ -414,7 +585,30 @@ impl<'pk2, B> Directory<'pk2, B> {
         self.entry().create_time.into_systime()
     }
 
+    /// Returns all times of this directory, as used by
+    /// [`Pk2Builder`](super::Pk2Builder).
+    pub fn times(&self) -> FileTimes {
+        let entry = self.entry();
+        FileTimes {
+            access_time: entry.access_time,
+            create_time: entry.create_time,
+            modify_time: entry.modify_time,
+        }
+    }
+}
+
+impl<'pk2, B> Directory<'pk2, B>
+where
+    B: Read + Seek,
+{
+    // returns the chain this folder represents
+    #[inline]
+    fn dir_chain(&self, chain: ChainIndex) -> Pk2Result<&'pk2 PackBlockChain> {
+        self.archive.load_chain(chain)
+    }
+
     pub fn open_file(&self, path: impl AsRef<Path>) -> Pk2Result<File<'pk2, B>> {
+        self.archive.load_path(self.chain, path.as_ref())?;
         let (chain, entry_idx, entry) = self
             .archive
             .block_manager
@@ -423,6 +617,7 @@ impl<'pk2, B> Directory<'pk2, B> {
     }
 
     pub fn open_directory(&self, path: impl AsRef<Path>) -> Pk2Result<Directory<'pk2, B>> {
+        self.archive.load_path(self.chain, path.as_ref())?;
         let (chain, entry_idx, entry) = self
             .archive
             .block_manager
@@ -440,6 +635,7 @@ impl<'pk2, B> Directory<'pk2, B> {
     }
 
     pub fn open(&self, path: impl AsRef<Path>) -> Pk2Result<DirEntry<'pk2, B>> {
+        self.archive.load_path(self.chain, path.as_ref())?;
         let (chain, entry_idx, entry) = self
             .archive
             .block_manager
@@ -447,24 +643,260 @@ impl<'pk2, B> Directory<'pk2, B> {
         DirEntry::from(entry, self.archive, chain, entry_idx).ok_or(Error::NotFound)
     }
 
-    /// Returns an iterator over all files in this directory.
-    pub fn files(&self) -> impl Iterator<Item = File<'pk2, B>> {
+    /// Returns an iterator over all entries below this directory whose paths
+    /// relative to it match the given glob pattern. The yielded paths are
+    /// relative to this directory as well.
+    pub fn glob(&self, pattern: &str) -> Pk2Result<Glob<'pk2, B>> {
+        Glob::new(Pattern::new(pattern)?, PathBuf::new(), self)
+    }
+
+    /// Returns an iterator over all files in this directory. Fails if the
+    /// chain of this directory can't be loaded.
+    pub fn files(&self) -> Pk2Result<impl Iterator<Item = File<'pk2, B>>> {
         let chain = self.entry().children_position();
         let archive = self.archive;
-        self.dir_chain(chain)
+        Ok(self
+            .dir_chain(chain)?
             .entries()
             .enumerate()
-            .flat_map(move |(idx, entry)| entry.as_file().map(|_| File::new(archive, chain, idx)))
+            .flat_map(move |(idx, entry)| entry.as_file().map(|_| File::new(archive, chain, idx))))
     }
 
     /// Returns an iterator over all items in this directory excluding `.` and
-    /// `..`.
-    pub fn entries(&self) -> impl Iterator<Item = DirEntry<'pk2, B>> {
+    /// `..`. Fails if the chain of this directory can't be loaded.
+    pub fn entries(&self) -> Pk2Result<impl Iterator<Item = DirEntry<'pk2, B>>> {
         let chain = self.entry().children_position();
         let archive = self.archive;
-        self.dir_chain(chain)
+        Ok(self
+            .dir_chain(chain)?
             .entries()
             .enumerate()
-            .flat_map(move |(idx, entry)| DirEntry::from(entry, archive, chain, idx))
+            .flat_map(move |(idx, entry)| DirEntry::from(entry, archive, chain, idx)))
+    }
+}
+
+/// A recursive iterator over the contents of a directory, created by
+/// [`Pk2::walk`]. Yields every file and directory below the base directory
+/// together with its path, the base directory itself is not yielded. A
+/// directory whose chain can't be loaded yields an error in place of its
+/// contents.
+///
+/// By default the walk is depth-first, yielding directories before their
+/// contents. Use [`Walk::events`] to be notified both when entering and when
+/// leaving a directory.
+///
+/// Every chain is walked at most once. A directory whose chain has been
+/// walked already, as happens in damaged archives where a directory links
+/// back to one of its ancestors, is yielded but not descended into.
+pub struct Walk<'pk2, B> {
+    // directories whose entries are being yielded, the current one is at the back when walking
+    // depth-first and at the front when walking breadth-first
+    frames: VecDeque<WalkFrame<'pk2, B>>,
+    // the directory entered last and whether to descend into it, its frame is only pushed on
+    // the next call to `next` so that it can still be skipped
+    pending: Option<(PathBuf, Directory<'pk2, B>, usize, bool)>,
+    // the chains of all directories that have been descended into
+    visited: HashSet<ChainIndex>,
+    breadth_first: bool,
+    contents_first: bool,
+    max_depth: usize,
+}
+
+struct WalkFrame<'pk2, B> {
+    path: PathBuf,
+    // the directory this frame belongs to, `None` for the base directory
+    dir: Option<Directory<'pk2, B>>,
+    entries: std::vec::IntoIter<DirEntry<'pk2, B>>,
+    // the depth of the entries of this frame
+    depth: usize,
+}
+
+/// An event of a [`Walk`], as yielded by [`WalkEvents`].
+pub enum WalkEvent<'pk2, B> {
+    /// A file, or a directory that is entered next.
+    Enter(PathBuf, DirEntry<'pk2, B>),
+    /// A directory that has been left after yielding its contents.
+    Exit(PathBuf, Directory<'pk2, B>),
+}
+
+impl<'pk2, B> Walk<'pk2, B>
+where
+    B: Read + Seek,
+{
+    pub(super) fn new(base: PathBuf, dir: Directory<'pk2, B>) -> Pk2Result<Self> {
+        let mut frames = VecDeque::new();
+        frames.push_back(WalkFrame {
+            path: base,
+            entries: dir.entries()?.collect::<Vec<_>>().into_iter(),
+            dir: None,
+            depth: 1,
+        });
+        let mut visited = HashSet::new();
+        visited.insert(dir.entry().children_position());
+        Ok(Walk {
+            frames,
+            pending: None,
+            visited,
+            breadth_first: false,
+            contents_first: false,
+            max_depth: usize::MAX,
+        })
+    }
+
+    /// Sets whether the tree is walked breadth-first, yielding all entries of
+    /// a directory before the entries of its subdirectories.
+    pub fn breadth_first(mut self, breadth_first: bool) -> Self {
+        self.breadth_first = breadth_first;
+        self
+    }
+
+    /// Sets whether directories are yielded after their contents instead of
+    /// before. Has no effect when walking breadth-first.
+    pub fn contents_first(mut self, contents_first: bool) -> Self {
+        self.contents_first = contents_first;
+        self
+    }
+
+    /// Sets the maximum depth of yielded entries, the entries of the base
+    /// directory are at depth 1. Directories at the maximum depth are yielded
+    /// but not descended into.
+    pub fn max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = max_depth;
+        self
+    }
+
+    /// Turns this walk into one that yields a [`WalkEvent::Enter`] before and
+    /// a [`WalkEvent::Exit`] after the contents of every directory. When
+    /// walking breadth-first a directory is left once its own entries have
+    /// been yielded. [`Walk::contents_first`] has no effect on the events.
+    pub fn events(self) -> WalkEvents<'pk2, B> {
+        WalkEvents(self)
+    }
+
+    /// Skips the contents of the directory that has been yielded last. Has no
+    /// effect if the last yielded entry was a file or if directories are
+    /// yielded after their contents.
+    pub fn skip_subtree(&mut self) {
+        if let Some((.., descend)) = &mut self.pending {
+            *descend = false;
+        }
+    }
+
+    fn push_frame(
+        &mut self,
+        path: PathBuf,
+        dir: Directory<'pk2, B>,
+        depth: usize,
+    ) -> Pk2Result<()> {
+        let entries = dir.entries()?.collect::<Vec<_>>().into_iter();
+        self.frames.push_back(WalkFrame {
+            path,
+            dir: Some(dir),
+            entries,
+            depth,
+        });
+        Ok(())
+    }
+
+    fn next_event(&mut self) -> Option<Pk2Result<WalkEvent<'pk2, B>>> {
+        if let Some((path, dir, depth, descend)) = self.pending.take() {
+            if !descend {
+                return Some(Ok(WalkEvent::Exit(path, dir)));
+            }
+            if let Err(e) = self.push_frame(path, dir, depth + 1) {
+                return Some(Err(e));
+            }
+        }
+        loop {
+            let frame = if self.breadth_first {
+                self.frames.front_mut()?
+            } else {
+                self.frames.back_mut()?
+            };
+            let depth = frame.depth;
+            let entry = if depth > self.max_depth {
+                None
+            } else {
+                frame.entries.next()
+            };
+            match entry {
+                Some(DirEntry::Directory(dir)) => {
+                    let path = frame.path.join(dir.name());
+                    let descend = depth < self.max_depth
+                        && self.visited.insert(dir.entry().children_position());
+                    let copy = Directory::new(dir.archive, dir.chain, dir.entry_index);
+                    self.pending = Some((path.clone(), copy, depth, descend));
+                    return Some(Ok(WalkEvent::Enter(path, DirEntry::Directory(dir))));
+                }
+                Some(DirEntry::File(file)) => {
+                    let path = frame.path.join(file.name());
+                    return Some(Ok(WalkEvent::Enter(path, DirEntry::File(file))));
+                }
+                None => {
+                    let frame = if self.breadth_first {
+                        self.frames.pop_front()
+                    } else {
+                        self.frames.pop_back()
+                    };
+                    if let Some(WalkFrame {
+                        path,
+                        dir: Some(dir),
+                        ..
+                    }) = frame
+                    {
+                        return Some(Ok(WalkEvent::Exit(path, dir)));
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl<'pk2, B> Iterator for Walk<'pk2, B>
+where
+    B: Read + Seek,
+{
+    type Item = Pk2Result<(PathBuf, DirEntry<'pk2, B>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let contents_first = self.contents_first && !self.breadth_first;
+        loop {
+            match self.next_event()? {
+                Ok(WalkEvent::Enter(_, DirEntry::Directory(_))) if contents_first => (),
+                Ok(WalkEvent::Enter(path, entry)) => return Some(Ok((path, entry))),
+                Ok(WalkEvent::Exit(path, dir)) if contents_first => {
+                    return Some(Ok((path, DirEntry::Directory(dir))))
+                }
+                Ok(WalkEvent::Exit(..)) => (),
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+/// A [`Walk`] yielding [`WalkEvent`]s, created by [`Walk::events`].
+pub struct WalkEvents<'pk2, B>(Walk<'pk2, B>);
+
+impl<B> WalkEvents<'_, B>
+where
+    B: Read + Seek,
+{
+    /// Skips the contents of the directory that has been entered last, it is
+    /// left right away. Has no effect if the last event wasn't entering a
+    /// directory.
+    pub fn skip_subtree(&mut self) {
+        self.0.skip_subtree();
+    }
+}
+
+impl<'pk2, B> Iterator for WalkEvents<'pk2, B>
+where
+    B: Read + Seek,
+{
+    type Item = Pk2Result<WalkEvent<'pk2, B>>;
+
+    #[inline]
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.next_event()
     }
 }
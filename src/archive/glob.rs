@@ -0,0 +1,250 @@
+//! Glob matching of archive paths.
+//!
+//! A pattern consists of `/` separated components which are matched against
+//! the path components of entries ignoring ASCII case, the same way paths are
+//! resolved. A component may contain `*` matching any sequence of characters,
+//! `?` matching a single character and `[...]` matching a single character of
+//! a set like `[abc]`, `[a-z]` or its complement `[!a-z]`. A component
+//! consisting of `**` matches any number of directories, including none.
+use std::io::{Read, Seek};
+use std::path::PathBuf;
+
+use super::fs::{DirEntry, Directory};
+use crate::error::{Error, Pk2Result};
+
+#[derive(Debug)]
+enum Component {
+    Recursive,
+    Pattern(Vec<char>),
+}
+
+impl Component {
+    fn matches(&self, name: &str) -> bool {
+        match self {
+            Component::Recursive => true,
+            Component::Pattern(pattern) => {
+                let name: Vec<char> = name.chars().collect();
+                matches_wildcard(pattern, &name)
+            }
+        }
+    }
+}
+
+fn matches_wildcard(pattern: &[char], name: &[char]) -> bool {
+    match pattern.first() {
+        None => name.is_empty(),
+        Some('*') => (0..=name.len()).any(|skip| matches_wildcard(&pattern[1..], &name[skip..])),
+        Some('?') => !name.is_empty() && matches_wildcard(&pattern[1..], &name[1..]),
+        Some('[') => {
+            let (negated, set_start) = match pattern.get(1) {
+                Some('!') => (true, 2),
+                _ => (false, 1),
+            };
+            // the first character of a set may be a `]`
+            let set_end = pattern
+                .get(set_start + 1..)
+                .and_then(|rest| rest.iter().position(|&c| c == ']'))
+                .map(|end| set_start + 1 + end);
+            match (set_end, name.first()) {
+                (Some(set_end), Some(&c)) => {
+                    matches_set(&pattern[set_start..set_end], c) != negated
+                        && matches_wildcard(&pattern[set_end + 1..], &name[1..])
+                }
+                (Some(_), None) => false,
+                // an unclosed set is matched literally
+                (None, _) => matches_literal(pattern, name),
+            }
+        }
+        Some(_) => matches_literal(pattern, name),
+    }
+}
+
+fn matches_literal(pattern: &[char], name: &[char]) -> bool {
+    match name.first() {
+        Some(c) if c.eq_ignore_ascii_case(&pattern[0]) => {
+            matches_wildcard(&pattern[1..], &name[1..])
+        }
+        _ => false,
+    }
+}
+
+fn matches_set(set: &[char], c: char) -> bool {
+    let c = c.to_ascii_lowercase();
+    let mut i = 0;
+    while i < set.len() {
+        let start = set[i].to_ascii_lowercase();
+        if set.get(i + 1) == Some(&'-') && i + 2 < set.len() {
+            let end = set[i + 2].to_ascii_lowercase();
+            if start <= c && c <= end {
+                return true;
+            }
+            i += 3;
+        } else {
+            if start == c {
+                return true;
+            }
+            i += 1;
+        }
+    }
+    false
+}
+
+/// A parsed glob pattern.
+#[derive(Debug)]
+pub(super) struct Pattern {
+    components: Vec<Component>,
+}
+
+impl Pattern {
+    /// Parses a pattern relative to the directory it gets matched in.
+    pub(super) fn new(pattern: &str) -> Pk2Result<Self> {
+        let mut components = Vec::new();
+        for component in pattern.split('/') {
+            match component {
+                "" | "." => (),
+                ".." => return Err(Error::InvalidPath),
+                "**" => components.push(Component::Recursive),
+                _ => components.push(Component::Pattern(component.chars().collect())),
+            }
+        }
+        Ok(Pattern { components })
+    }
+
+    /// Adds every position that can be reached by letting a `**` match no
+    /// directory at all.
+    fn close(&self, positions: &mut Vec<usize>) {
+        let mut i = 0;
+        while i < positions.len() {
+            let pos = positions[i];
+            if let Some(Component::Recursive) = self.components.get(pos) {
+                if !positions.contains(&(pos + 1)) {
+                    positions.push(pos + 1);
+                }
+            }
+            i += 1;
+        }
+    }
+
+    /// Whether an entry named `name` matches the whole pattern when reached
+    /// with the given positions, and the positions to continue with in case
+    /// it is a directory.
+    fn advance(&self, positions: &[usize], name: &str) -> (bool, Vec<usize>) {
+        let last = self.components.len();
+        let mut matched = false;
+        let mut next = Vec::new();
+        for &pos in positions {
+            let component = match self.components.get(pos) {
+                Some(component) if component.matches(name) => component,
+                _ => continue,
+            };
+            if let Component::Recursive = component {
+                if !next.contains(&pos) {
+                    next.push(pos);
+                }
+            }
+            if pos + 1 == last {
+                matched = true;
+            } else if !next.contains(&(pos + 1)) {
+                next.push(pos + 1);
+            }
+        }
+        self.close(&mut next);
+        (matched, next)
+    }
+}
+
+/// An iterator over all entries below a directory whose paths match a glob
+/// pattern, created by [`Pk2::glob`](super::Pk2::glob) or
+/// [`Directory::glob`]. Only directories that can contain matches are
+/// descended into, one whose chain can't be loaded yields an error.
+pub struct Glob<'pk2, B> {
+    pattern: Pattern,
+    frames: Vec<GlobFrame<'pk2, B>>,
+}
+
+struct GlobFrame<'pk2, B> {
+    path: PathBuf,
+    // the positions in the pattern the entries of this frame are matched at
+    positions: Vec<usize>,
+    entries: std::vec::IntoIter<DirEntry<'pk2, B>>,
+}
+
+impl<'pk2, B> Glob<'pk2, B>
+where
+    B: Read + Seek,
+{
+    pub(super) fn new(
+        pattern: Pattern,
+        base: PathBuf,
+        dir: &Directory<'pk2, B>,
+    ) -> Pk2Result<Self> {
+        let mut positions = vec![0];
+        pattern.close(&mut positions);
+        let frames = vec![GlobFrame {
+            path: base,
+            positions,
+            entries: dir.entries()?.collect::<Vec<_>>().into_iter(),
+        }];
+        Ok(Glob { pattern, frames })
+    }
+}
+
+impl<'pk2, B> Iterator for Glob<'pk2, B>
+where
+    B: Read + Seek,
+{
+    type Item = Pk2Result<(PathBuf, DirEntry<'pk2, B>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let frame = self.frames.last_mut()?;
+            let entry = match frame.entries.next() {
+                Some(entry) => entry,
+                None => {
+                    self.frames.pop();
+                    continue;
+                }
+            };
+            let (matched, positions) = self.pattern.advance(&frame.positions, entry.name());
+            let path = frame.path.join(entry.name());
+            if let DirEntry::Directory(dir) = &entry {
+                if !positions.is_empty() {
+                    let entries = match dir.entries() {
+                        Ok(entries) => entries.collect::<Vec<_>>().into_iter(),
+                        Err(e) => return Some(Err(e)),
+                    };
+                    self.frames.push(GlobFrame {
+                        path: path.clone(),
+                        positions,
+                        entries,
+                    });
+                }
+            }
+            if matched {
+                return Some(Ok((path, entry)));
+            }
+        }
+    }
+}
+
+#[test]
+fn test_glob_pattern_matching() {
+    let matches =
+        |pattern: &str, name: &str| Component::Pattern(pattern.chars().collect()).matches(name);
+    assert!(matches("*.DDJ", "stone.ddj"));
+    assert!(!matches("*.ddj", "stone.ddj.bak"));
+    assert!(matches("s?one*", "Stone.ddj"));
+    assert!(matches("[a-c]*", "Bark.txt"));
+    assert!(!matches("[!a-c]*", "bark.txt"));
+    assert!(matches("[x", "[X"));
+
+    let pattern = Pattern::new("prim/**/*.ddj").unwrap();
+    let mut positions = vec![0];
+    pattern.close(&mut positions);
+    let (matched, positions) = pattern.advance(&positions, "Prim");
+    assert!(!matched);
+    assert!(pattern.advance(&positions, "a.ddj").0);
+    let (_, positions) = pattern.advance(&positions, "mtrl");
+    assert!(pattern.advance(&positions, "b.DDJ").0);
+    assert!(pattern.advance(&[0], "other").1.is_empty());
+}
@@ -0,0 +1,355 @@
+//! An undo journal that allows rolling back modifications of an archive that
+//! were interrupted midway.
+//!
+//! Before any byte of the archive that existed at the start of a transaction
+//! gets overwritten, its previous content is appended to the journal and the
+//! journal is synced to disk. Committing a transaction syncs the archive and
+//! then empties the journal. A non-empty journal therefore always describes
+//! how to restore the archive to the state of the last commit.
+use byteorder::{ReadBytesExt, WriteBytesExt, LE};
+
+use std::ffi::OsString;
+use std::fs as stdfs;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use super::Truncate;
+
+const JOURNAL_MAGIC: &[u8; 8] = b"PK2UNDO\0";
+
+pub(super) struct Journal {
+    path: PathBuf,
+    file: stdfs::File,
+    // a second handle to the archive, used to sync it to disk when committing
+    archive: stdfs::File,
+    // the length of the archive at the start of the current transaction, if one is in progress
+    transaction: Option<u64>,
+}
+
+impl Journal {
+    /// The path of the journal belonging to the archive at `archive_path`.
+    fn path_for(archive_path: &Path) -> PathBuf {
+        let mut path = OsString::from(archive_path.as_os_str());
+        path.push(".journal");
+        PathBuf::from(path)
+    }
+
+    /// Creates the journal for the given archive. The archive has to be
+    /// recovered beforehand.
+    pub(super) fn create(archive_path: &Path, archive: &stdfs::File) -> io::Result<Self> {
+        let path = Self::path_for(archive_path);
+        let file = stdfs::OpenOptions::new()
+            .create(true)
+            .truncate(true)
+            .read(true)
+            .write(true)
+            .open(&path)?;
+        Ok(Journal {
+            path,
+            file,
+            archive: archive.try_clone()?,
+            transaction: None,
+        })
+    }
+
+    /// Rolls the archive back to the state of its last commit if its journal
+    /// contains an interrupted transaction.
+    pub(super) fn recover(archive_path: &Path, archive: &mut stdfs::File) -> io::Result<()> {
+        let path = Self::path_for(archive_path);
+        let mut data = Vec::new();
+        match stdfs::File::open(&path) {
+            Ok(mut file) => file.read_to_end(&mut data)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        // a torn header means the transaction had not touched the archive yet
+        if let Some((archive_len, records)) = parse_journal(&data) {
+            for (offset, bytes) in records.iter().rev() {
+                archive.seek(SeekFrom::Start(*offset))?;
+                archive.write_all(bytes)?;
+            }
+            archive.set_len(archive_len)?;
+            archive.sync_all()?;
+        }
+        stdfs::remove_file(&path)
+    }
+
+    /// Whether the journal of the given archive contains an interrupted
+    /// transaction that has yet to be rolled back.
+    pub(super) fn is_pending(archive_path: &Path) -> io::Result<bool> {
+        let mut data = Vec::new();
+        match stdfs::File::open(Self::path_for(archive_path)) {
+            Ok(mut file) => file.read_to_end(&mut data)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(e),
+        };
+        Ok(parse_journal(&data).is_some())
+    }
+
+    /// Records the current content of the archive in the range `offset..offset
+    /// + len` so that it can be restored later on.
+    fn record<F: Read + Seek>(&mut self, archive: &mut F, offset: u64, len: u64) -> io::Result<()> {
+        let archive_len = match self.transaction {
+            Some(archive_len) => archive_len,
+            None => self.begin(archive)?,
+        };
+        // everything past the original end gets removed on rollback anyways
+        let end = (offset + len).min(archive_len);
+        if offset >= end {
+            return Ok(());
+        }
+        let mut bytes = vec![0; (end - offset) as usize];
+        archive.seek(SeekFrom::Start(offset))?;
+        archive.read_exact(&mut bytes)?;
+
+        let mut record = Vec::with_capacity(bytes.len() + 20);
+        record.write_u64::<LE>(offset)?;
+        record.write_u32::<LE>(bytes.len() as u32)?;
+        record.extend_from_slice(&bytes);
+        let checksum = fnv1a(&record);
+        record.write_u64::<LE>(checksum)?;
+        self.file.write_all(&record)?;
+        self.file.sync_data()
+    }
+
+    fn begin<F: Seek>(&mut self, archive: &mut F) -> io::Result<u64> {
+        let archive_len = archive.seek(SeekFrom::End(0))?;
+        let mut header = Vec::with_capacity(24);
+        header.extend_from_slice(JOURNAL_MAGIC);
+        header.write_u64::<LE>(archive_len)?;
+        header.write_u64::<LE>(fnv1a(&header))?;
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.write_all(&header)?;
+        self.file.sync_data()?;
+        self.transaction = Some(archive_len);
+        Ok(archive_len)
+    }
+
+    /// Whether a transaction has been started and not been committed yet.
+    #[inline]
+    pub(super) fn in_transaction(&self) -> bool {
+        self.transaction.is_some()
+    }
+
+    /// Makes all changes since the start of the current transaction permanent.
+    pub(super) fn commit<F: Write>(&mut self, archive: &mut F) -> io::Result<()> {
+        if self.transaction.is_none() {
+            return Ok(());
+        }
+        archive.flush()?;
+        self.archive.sync_data()?;
+        self.file.set_len(0)?;
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.sync_data()?;
+        self.transaction = None;
+        Ok(())
+    }
+}
+
+impl Drop for Journal {
+    fn drop(&mut self) {
+        // keep the journal of an unfinished transaction around so that it can be rolled back
+        if self.transaction.is_none() {
+            let _ = stdfs::remove_file(&self.path);
+        }
+    }
+}
+
+/// The offset and previous content of an overwritten region of the archive.
+type UndoRecord<'a> = (u64, &'a [u8]);
+
+/// Parses a journal into the archive length at the start of the transaction
+/// and the list of undo records. Parsing stops at the first incomplete or
+/// damaged record, as its write to the archive can't have happened yet.
+fn parse_journal(mut data: &[u8]) -> Option<(u64, Vec<UndoRecord<'_>>)> {
+    if data.len() < 24
+        || &data[..8] != JOURNAL_MAGIC
+        || fnv1a(&data[..16]) != (&data[16..]).read_u64::<LE>().ok()?
+    {
+        return None;
+    }
+    let archive_len = (&data[8..]).read_u64::<LE>().ok()?;
+    data = &data[24..];
+    let mut records = Vec::new();
+    while data.len() >= 12 {
+        let offset = (&data[..]).read_u64::<LE>().ok()?;
+        let len = (&data[8..]).read_u32::<LE>().ok()? as usize;
+        let record_len = 12 + len;
+        if data.len() < record_len + 8 {
+            break;
+        }
+        let checksum = (&data[record_len..]).read_u64::<LE>().ok()?;
+        if fnv1a(&data[..record_len]) != checksum {
+            break;
+        }
+        records.push((offset, &data[12..record_len]));
+        data = &data[record_len + 8..];
+    }
+    Some((archive_len, records))
+}
+
+fn fnv1a(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &b| {
+        (hash ^ b as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// A handle for writing to the archive file that records everything it is
+/// about to overwrite in the journal, if there is one.
+pub(super) struct JournaledFile<'a, B> {
+    file: &'a mut B,
+    journal: Option<&'a mut Journal>,
+}
+
+impl<'a, B> JournaledFile<'a, B> {
+    pub(super) fn new(file: &'a mut B, journal: Option<&'a mut Journal>) -> Self {
+        JournaledFile { file, journal }
+    }
+}
+
+impl<B: Read> Read for JournaledFile<'_, B> {
+    #[inline]
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.file.read(buf)
+    }
+}
+
+impl<B: Seek> Seek for JournaledFile<'_, B> {
+    #[inline]
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.file.seek(pos)
+    }
+}
+
+impl<B: Read + Write + Seek> Write for JournaledFile<'_, B> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if let Some(journal) = self.journal.as_mut() {
+            let pos = self.file.stream_position()?;
+            journal.record(self.file, pos, buf.len() as u64)?;
+            self.file.seek(SeekFrom::Start(pos))?;
+        }
+        self.file.write(buf)
+    }
+
+    #[inline]
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl<B: Write + Truncate> Truncate for JournaledFile<'_, B> {
+    /// Truncation can't be undone, so the current transaction gets committed
+    /// beforehand.
+    fn truncate(&mut self, len: u64) -> io::Result<()> {
+        if let Some(journal) = self.journal.as_mut() {
+            journal.commit(self.file)?;
+        }
+        self.file.truncate(len)
+    }
+}
+
+#[test]
+fn test_journal_recover() {
+    let path = std::env::temp_dir().join(format!("pk2_journal_test_{}", std::process::id()));
+    stdfs::write(&path, b"hello world").unwrap();
+    let mut archive = stdfs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(&path)
+        .unwrap();
+    let mut journal = Journal::create(&path, &archive).unwrap();
+    {
+        let mut file = JournaledFile::new(&mut archive, Some(&mut journal));
+        file.seek(SeekFrom::Start(6)).unwrap();
+        file.write_all(b"there, and more").unwrap();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.write_all(b"HELLO").unwrap();
+    }
+    // simulate a crash before the transaction got committed
+    std::mem::forget(journal);
+    Journal::recover(&path, &mut archive).unwrap();
+    assert_eq!(stdfs::read(&path).unwrap(), b"hello world");
+    assert!(!Journal::path_for(&path).exists());
+    stdfs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_journaled_archive_recover() {
+    use super::{OpenOptions, Pk2};
+    let path = std::env::temp_dir().join(format!("pk2_journal_archive_{}", std::process::id()));
+    let _ = stdfs::remove_file(&path);
+    let mut archive = OpenOptions::new()
+        .journal(true)
+        .create_new(&path, "169841")
+        .unwrap();
+    archive
+        .create_file("/dir/file")
+        .unwrap()
+        .write_all(b"contents")
+        .unwrap();
+    let committed = stdfs::read(&path).unwrap();
+    {
+        // overwrite the header and root block and grow the archive without
+        // committing, as if the process crashed halfway through an operation
+        let mut file = archive.file.get_mut();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.write_all(&[0xAA; 1024]).unwrap();
+        file.seek(SeekFrom::End(0)).unwrap();
+        file.write_all(&[0xBB; 4096]).unwrap();
+    }
+    drop(archive);
+    assert!(Journal::path_for(&path).exists());
+    assert!(Pk2::open_readonly(&path, "169841").is_err());
+
+    let archive = OpenOptions::new().open(&path, "169841").unwrap();
+    assert!(!Journal::path_for(&path).exists());
+    assert_eq!(stdfs::read(&path).unwrap(), committed);
+    let mut contents = String::new();
+    archive
+        .open_file("/dir/file")
+        .unwrap()
+        .read_to_string(&mut contents)
+        .unwrap();
+    assert_eq!(contents, "contents");
+    drop(archive);
+    stdfs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_journaled_archive_poisoned() {
+    use super::{OpenOptions, Pk2};
+    let path = std::env::temp_dir().join(format!("pk2_journal_poison_{}", std::process::id()));
+    let _ = stdfs::remove_file(&path);
+    let mut archive = OpenOptions::new()
+        .journal(true)
+        .create_new(&path, "169841")
+        .unwrap();
+    archive
+        .create_file("/file")
+        .unwrap()
+        .write_all(b"contents")
+        .unwrap();
+    let committed = stdfs::read(&path).unwrap();
+    {
+        // an operation that wrote the header and failed before committing
+        let mut file = archive.file.get_mut();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.write_all(&[0xAA; 256]).unwrap();
+    }
+    assert!(archive.create_directory("/dir").is_err());
+    assert!(archive.delete_file("/file").is_err());
+    drop(archive);
+    assert_eq!(
+        stdfs::read(&path).unwrap(),
+        [&[0xAA; 256][..], &committed[256..]].concat()
+    );
+
+    // the key is detected on the recovered header
+    let (archive, key) = OpenOptions::new().open_detect_key(&path).unwrap();
+    assert_eq!(key, b"169841");
+    assert!(archive.open_file("/file").is_ok());
+    drop(archive);
+    assert_eq!(stdfs::read(&path).unwrap(), committed);
+    let _ = Pk2::open_readonly(&path, "169841").unwrap();
+    stdfs::remove_file(&path).unwrap();
+}
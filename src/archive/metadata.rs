@@ -0,0 +1,160 @@
+//! Custom metadata stored in the reserved area of the archive header.
+use byteorder::{ByteOrder, LE};
+
+use std::io::{Read, Seek, SeekFrom, Write};
+
+use super::Pk2;
+use crate::error::{Error, Pk2Result};
+use crate::io::RawIo;
+use crate::raw::header::PackHeader;
+
+// Layout of the reserved area of the header:
+// 0..4   magic
+// 4      layout version
+// layout version 1:
+// 5..13  build id
+// 13..17 content version
+// 17     length of the tool name
+// 18..   tool name, utf-8
+// Later layout versions only append fields after the tool name, so readers
+// read the fields of the versions they know and skip the rest.
+const METADATA_MAGIC: &[u8; 4] = b"PK2M";
+const METADATA_LAYOUT_VERSION: u8 = 1;
+const METADATA_TOOL_OFFSET: usize = 18;
+
+/// A small record describing the contents of an archive, stored in the
+/// otherwise unused reserved bytes of its header. Clients ignore this area,
+/// so archives carrying metadata stay compatible.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Metadata {
+    /// An identifier of the build that produced the archive.
+    pub build_id: u64,
+    /// The patch level of the contents of the archive.
+    pub content_version: u32,
+    /// The name of the tool that created the archive, at most
+    /// [`Metadata::MAX_TOOL_LEN`] bytes long.
+    pub tool: String,
+}
+
+impl Metadata {
+    /// The maximum length of [`Metadata::tool`] in bytes.
+    pub const MAX_TOOL_LEN: usize = 205 - METADATA_TOOL_OFFSET;
+
+    /// Reads the metadata from the header of the archive in `reader` without
+    /// loading its index, returning `None` if the archive has none. The
+    /// signature of the archive is not validated.
+    pub fn read_from<R: Read + Seek>(mut reader: R) -> Pk2Result<Option<Self>> {
+        reader.seek(SeekFrom::Start(0))?;
+        let header = PackHeader::from_reader(&mut reader)?;
+        Self::from_reserved(&header.reserved)
+    }
+
+    fn from_reserved(reserved: &[u8; 205]) -> Pk2Result<Option<Self>> {
+        if &reserved[..4] != METADATA_MAGIC {
+            return Ok(None);
+        }
+        if reserved[4] < METADATA_LAYOUT_VERSION {
+            return Err(Error::UnsupportedMetadataVersion);
+        }
+        let tool_len = reserved[METADATA_TOOL_OFFSET - 1] as usize;
+        let tool = reserved
+            .get(METADATA_TOOL_OFFSET..METADATA_TOOL_OFFSET + tool_len)
+            .and_then(|tool| std::str::from_utf8(tool).ok())
+            .ok_or(Error::InvalidMetadata)?;
+        Ok(Some(Metadata {
+            build_id: LE::read_u64(&reserved[5..13]),
+            content_version: LE::read_u32(&reserved[13..17]),
+            tool: tool.to_owned(),
+        }))
+    }
+
+    fn to_reserved(&self) -> Pk2Result<[u8; 205]> {
+        if self.tool.len() > Self::MAX_TOOL_LEN {
+            return Err(Error::InvalidMetadata);
+        }
+        let mut reserved = [0; 205];
+        reserved[..4].copy_from_slice(METADATA_MAGIC);
+        reserved[4] = METADATA_LAYOUT_VERSION;
+        LE::write_u64(&mut reserved[5..13], self.build_id);
+        LE::write_u32(&mut reserved[13..17], self.content_version);
+        reserved[METADATA_TOOL_OFFSET - 1] = self.tool.len() as u8;
+        reserved[METADATA_TOOL_OFFSET..][..self.tool.len()].copy_from_slice(self.tool.as_bytes());
+        Ok(reserved)
+    }
+}
+
+impl<B> Pk2<B>
+where
+    B: Read + Seek,
+{
+    /// Returns the metadata stored in the header of this archive, or `None`
+    /// if it has none.
+    pub fn metadata(&self) -> Pk2Result<Option<Metadata>> {
+        let mut file = self.file.lock();
+        file.seek(SeekFrom::Start(0))?;
+        let header = PackHeader::from_reader(&mut *file)?;
+        Metadata::from_reserved(&header.reserved)
+    }
+}
+
+impl<B> Pk2<B>
+where
+    B: Read + Write + Seek,
+{
+    /// Stores `metadata` in the header of this archive, replacing any
+    /// previously stored metadata. `None` removes the metadata, zeroing the
+    /// reserved area of the header again.
+    pub fn set_metadata(&mut self, metadata: Option<&Metadata>) -> Pk2Result<()> {
+        self.file.begin()?;
+        let reserved = match metadata {
+            Some(metadata) => metadata.to_reserved()?,
+            None => [0; 205],
+        };
+        let mut file = self.file.get_mut();
+        file.seek(SeekFrom::Start(0))?;
+        let mut header = PackHeader::from_reader(&mut file)?;
+        header.reserved = reserved;
+        file.seek(SeekFrom::Start(0))?;
+        header.to_writer(&mut file)?;
+        self.file.commit()?;
+        Ok(())
+    }
+}
+
+#[test]
+fn test_metadata_roundtrip() {
+    let mut buf = std::io::Cursor::new(Vec::new());
+    let mut archive = Pk2::create_new_in(&mut buf, "169841").unwrap();
+    assert_eq!(archive.metadata().unwrap(), None);
+    let metadata = Metadata {
+        build_id: 0x1234_5678_9abc,
+        content_version: 188,
+        tool: "pk2_mate".to_owned(),
+    };
+    archive.set_metadata(Some(&metadata)).unwrap();
+    assert_eq!(archive.metadata().unwrap().as_ref(), Some(&metadata));
+    drop(archive);
+    assert_eq!(Metadata::read_from(&mut buf).unwrap(), Some(metadata));
+    let mut archive = Pk2::open_in(&mut buf, "169841").unwrap();
+    archive.set_metadata(None).unwrap();
+    assert_eq!(archive.metadata().unwrap(), None);
+}
+
+#[test]
+fn test_metadata_layout_versions() {
+    let metadata = Metadata {
+        build_id: 7,
+        content_version: 188,
+        tool: "pk2_mate".to_owned(),
+    };
+    let mut reserved = metadata.to_reserved().unwrap();
+    // a newer layout appending a field after the tool name
+    reserved[4] = METADATA_LAYOUT_VERSION + 1;
+    reserved[METADATA_TOOL_OFFSET + metadata.tool.len()] = 0xFF;
+    assert_eq!(Metadata::from_reserved(&reserved).unwrap(), Some(metadata));
+    reserved[4] = 0;
+    assert!(matches!(
+        Metadata::from_reserved(&reserved),
+        Err(Error::UnsupportedMetadataVersion)
+    ));
+}
@@ -0,0 +1,136 @@
+//! Read-only memory mapped archive backend.
+use std::convert::TryFrom;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::ops::Deref;
+use std::path::Path;
+use std::sync::Arc;
+use std::{fmt, fs as stdfs};
+
+use memmap2::Mmap;
+
+use crate::archive::{OpenOptions, Pk2};
+use crate::error::Pk2Result;
+use crate::Key;
+
+/// A read-only archive file whose contents are memory mapped. The index is
+/// read through the file while the data of files is read straight from the
+/// map, which the archive keeps next to the file.
+pub struct MappedFile(stdfs::File);
+
+impl Read for MappedFile {
+    #[inline]
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl Seek for MappedFile {
+    #[inline]
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.0.seek(pos)
+    }
+}
+
+/// A view into the data of a file of a memory mapped archive. This keeps the
+/// underlying map alive and can therefore outlive the archive it came from.
+#[derive(Clone)]
+pub struct MappedBytes {
+    map: Arc<Mmap>,
+    start: usize,
+    end: usize,
+}
+
+impl MappedBytes {
+    /// Returns a view of `len` bytes of `map` at `offset`, or `None` if they
+    /// lie outside of the map.
+    pub(crate) fn new(map: &Arc<Mmap>, offset: u64, len: u64) -> Option<Self> {
+        let start = usize::try_from(offset).ok()?;
+        let end = start.checked_add(usize::try_from(len).ok()?)?;
+        if end > map.len() {
+            return None;
+        }
+        Some(MappedBytes {
+            map: map.clone(),
+            start,
+            end,
+        })
+    }
+}
+
+impl Deref for MappedBytes {
+    type Target = [u8];
+    #[inline]
+    fn deref(&self) -> &[u8] {
+        &self.map[self.start..self.end]
+    }
+}
+
+impl AsRef<[u8]> for MappedBytes {
+    #[inline]
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+impl fmt::Debug for MappedBytes {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("MappedBytes")
+            .field("start", &self.start)
+            .field("end", &self.end)
+            .finish()
+    }
+}
+
+impl Pk2<MappedFile> {
+    /// Opens the archive at the given path as a read-only memory map.
+    ///
+    /// # Safety
+    ///
+    /// The archive file must not be modified, truncated or deleted by this or
+    /// any other process while the returned archive or any [`MappedBytes`]
+    /// obtained from it are alive.
+    pub unsafe fn open_mapped<P: AsRef<Path>, K: AsRef<[u8]>>(path: P, key: K) -> Pk2Result<Self> {
+        Self::open_mapped_with_key(path, Key::from(key.as_ref()))
+    }
+
+    /// Like [`Pk2::open_mapped`], but takes a [`Key`] which allows for custom
+    /// salts and raw keys.
+    ///
+    /// # Safety
+    ///
+    /// See [`Pk2::open_mapped`].
+    pub unsafe fn open_mapped_with_key<P: AsRef<Path>>(path: P, key: Key<'_>) -> Pk2Result<Self> {
+        let file = stdfs::File::open(path)?;
+        let map = Mmap::map(&file)?;
+        let mut archive = OpenOptions::new().open_in_with_key(MappedFile(file), key)?;
+        archive.file.map = Some(Arc::new(map));
+        Ok(archive)
+    }
+}
+
+#[test]
+fn test_mapped_roundtrip() {
+    use std::io::Write;
+    let path = std::env::temp_dir().join(format!("pk2_mmap_{}.pk2", std::process::id()));
+    let mut archive = Pk2::create_new(&path, "169841").unwrap();
+    let data: Vec<u8> = (0..5000u32).map(|i| i as u8).collect();
+    archive
+        .create_file("/dir/file")
+        .unwrap()
+        .write_all(&data)
+        .unwrap();
+    drop(archive);
+
+    let archive = unsafe { Pk2::open_mapped(&path, "169841") }.unwrap();
+    let mut file = archive.open_file("/dir/file").unwrap();
+    assert_eq!(&*file.as_bytes().unwrap(), &data[..]);
+    let mut read = Vec::new();
+    file.read_to_end(&mut read).unwrap();
+    assert_eq!(read, data);
+    let mut buf = [0; 100];
+    file.seek(SeekFrom::Start(4950)).unwrap();
+    assert_eq!(file.read(&mut buf).unwrap(), 50);
+    assert_eq!(&buf[..50], &data[4950..]);
+    drop(archive);
+    stdfs::remove_file(&path).unwrap();
+}
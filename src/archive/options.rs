@@ -0,0 +1,344 @@
+use std::path::Path;
+use std::time::SystemTime;
+use std::{fs as stdfs, io};
+
+use super::journal::Journal;
+use super::{FileTimes, Pk2, ReadOnly};
+use crate::constants::{PK2_KNOWN_KEYS, PK2_SIGNATURE, PK2_VERSION};
+use crate::error::{Error, Pk2Result};
+use crate::raw::header::PackHeader;
+use crate::{Encoding, Key, FILETIME};
+
+/// Options and flags which can be used to configure how an archive is opened
+/// or created.
+#[derive(Clone, Debug)]
+pub struct OpenOptions {
+    pub(super) journal: bool,
+    pub(super) lazy: bool,
+    pub(super) tolerant: bool,
+    pub(super) encoding: Encoding,
+    pub(super) signature: [u8; 30],
+    pub(super) version: u32,
+    accepted_signatures: Vec<[u8; 30]>,
+    accepted_versions: Vec<u32>,
+    ignore_signature: bool,
+    invalid_signature: bool,
+    pub(super) fixed_time: Option<FILETIME>,
+}
+
+impl Default for OpenOptions {
+    fn default() -> Self {
+        OpenOptions {
+            journal: false,
+            lazy: false,
+            tolerant: false,
+            encoding: Encoding::default(),
+            signature: *PK2_SIGNATURE,
+            version: PK2_VERSION,
+            accepted_signatures: vec![*PK2_SIGNATURE],
+            accepted_versions: vec![PK2_VERSION],
+            ignore_signature: false,
+            invalid_signature: false,
+            fixed_time: None,
+        }
+    }
+}
+
+impl OpenOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets whether modifications of the archive should be journaled. A
+    /// journaled archive keeps an undo journal next to it, at its path with
+    /// `.journal` appended, that allows rolling back an operation that got
+    /// interrupted midway the next time the archive is opened. This makes
+    /// writing considerably slower as the journal gets synced to disk before
+    /// every write. An operation that fails midway is treated the same, every
+    /// further modification fails until the archive gets reopened.
+    ///
+    /// Only has an effect for archives opened or created by path.
+    pub fn journal(&mut self, journal: bool) -> &mut Self {
+        self.journal = journal;
+        self
+    }
+
+    /// Sets whether the index of the archive should be loaded lazily. Instead
+    /// of reading and decrypting every block chain up front, chains get
+    /// loaded on first access and are cached afterwards. This makes opening
+    /// huge archives to access a few paths a lot faster.
+    ///
+    /// Operations that require the complete index, like
+    /// [`Pk2::compact`](super::Pk2::compact) or deleting a directory
+    /// recursively, load the remaining chains when invoked. Until then, freed
+    /// regions of the archive are only reused if they were freed by the
+    /// archive itself.
+    pub fn lazy(&mut self, lazy: bool) -> &mut Self {
+        self.lazy = lazy;
+        self
+    }
+
+    /// Sets whether damaged archives should be opened anyways. Block chains
+    /// whose blocks link to each other in a cycle or to blocks that can't be
+    /// read are cut short, entries of unknown types are treated as empty and
+    /// directories whose chains can't be read are ignored. These changes are
+    /// only made in memory, [`Pk2::repair`](super::Pk2::repair) writes them
+    /// out. A tolerantly opened archive is never loaded lazily.
+    pub fn tolerant(&mut self, tolerant: bool) -> &mut Self {
+        self.tolerant = tolerant;
+        self
+    }
+
+    /// Sets the encoding entry names are stored in, defaults to
+    /// [`Encoding::EucKr`] which is used by the korean and international
+    /// clients. Archives of other regions have to be opened with their
+    /// respective encoding, otherwise names with non-ASCII characters get
+    /// garbled.
+    pub fn encoding(&mut self, encoding: Encoding) -> &mut Self {
+        self.encoding = encoding;
+        self
+    }
+
+    /// Sets the signature written into the header of created archives,
+    /// defaults to [`PK2_SIGNATURE`]. Archives with this signature are
+    /// accepted when opening as well. Signatures shorter than 30 bytes are
+    /// padded with zeroes, signatures longer than that make opening and
+    /// creating archives fail with [`Error::InvalidSignature`].
+    pub fn signature<S: AsRef<[u8]>>(&mut self, signature: S) -> &mut Self {
+        if let Some(padded) = pad_signature(signature.as_ref()) {
+            self.signature = padded;
+        }
+        self.accept_signature(signature)
+    }
+
+    /// Sets the version written into the header of created archives, defaults
+    /// to [`PK2_VERSION`]. Archives with this version are accepted when
+    /// opening as well.
+    pub fn version(&mut self, version: u32) -> &mut Self {
+        self.version = version;
+        self.accept_version(version)
+    }
+
+    /// Accepts archives with the given signature when opening, in addition
+    /// to [`PK2_SIGNATURE`]. Can be called multiple times to accept several
+    /// signatures. Signatures shorter than 30 bytes are padded with zeroes,
+    /// signatures longer than that make opening and creating archives fail
+    /// with [`Error::InvalidSignature`].
+    pub fn accept_signature<S: AsRef<[u8]>>(&mut self, signature: S) -> &mut Self {
+        match pad_signature(signature.as_ref()) {
+            Some(signature) if !self.accepted_signatures.contains(&signature) => {
+                self.accepted_signatures.push(signature)
+            }
+            Some(_) => (),
+            None => self.invalid_signature = true,
+        }
+        self
+    }
+
+    /// Accepts archives with the given version when opening, in addition to
+    /// [`PK2_VERSION`]. Can be called multiple times to accept several
+    /// versions.
+    pub fn accept_version(&mut self, version: u32) -> &mut Self {
+        if !self.accepted_versions.contains(&version) {
+            self.accepted_versions.push(version);
+        }
+        self
+    }
+
+    /// Sets whether the signature and version of an archive should be ignored
+    /// when opening it, accepting archives of any signature and version.
+    pub fn ignore_signature(&mut self, ignore_signature: bool) -> &mut Self {
+        self.ignore_signature = ignore_signature;
+        self
+    }
+
+    /// Sets a time that is used instead of the current time whenever an
+    /// entry gets created or modified, so that performing the same operations
+    /// on the same archive produces identical bytes. `None`, the default,
+    /// uses the current time.
+    pub fn fixed_time(&mut self, time: Option<SystemTime>) -> &mut Self {
+        self.fixed_time = time.map(FILETIME::from);
+        self
+    }
+
+    pub(super) fn default_times(&self) -> FileTimes {
+        FileTimes::from_filetime(self.fixed_time.unwrap_or_else(FILETIME::now))
+    }
+
+    pub(super) fn validate_header(&self, header: &PackHeader) -> Pk2Result<()> {
+        if self.ignore_signature {
+            Ok(())
+        } else {
+            header.validate_sig_with(&self.accepted_signatures, &self.accepted_versions)
+        }
+    }
+
+    /// Opens the archive at the given path. If a journal is found next to the
+    /// archive, the interrupted operation it describes is rolled back first
+    /// regardless of whether journaling is enabled.
+    pub fn open<P: AsRef<Path>, K: AsRef<[u8]>>(&self, path: P, key: K) -> Pk2Result<Pk2> {
+        self.open_with_key(path, Key::from(key.as_ref()))
+    }
+
+    /// Like [`OpenOptions::open`], but takes a [`Key`] which allows for custom
+    /// salts and raw keys.
+    pub fn open_with_key<P: AsRef<Path>>(&self, path: P, key: Key<'_>) -> Pk2Result<Pk2> {
+        self.validate()?;
+        let path = path.as_ref();
+        let file = Self::open_recovered(path)?;
+        self.open_journaled(path, file, key)
+    }
+
+    /// Opens the archive at the given path with the first key of
+    /// [`PK2_KNOWN_KEYS`] it is encrypted with, see [`Pk2::open_detect_key`].
+    ///
+    /// [`PK2_KNOWN_KEYS`]: crate::constants::PK2_KNOWN_KEYS
+    pub fn open_detect_key<P: AsRef<Path>>(&self, path: P) -> Pk2Result<(Pk2, &'static [u8])> {
+        use std::io::Seek;
+        self.validate()?;
+        let path = path.as_ref();
+        // the key has to be detected on the recovered header
+        let mut file = Self::open_recovered(path)?;
+        let keys = PK2_KNOWN_KEYS.iter().map(|&(_, key)| key);
+        let key = Pk2::detect_key(&mut file, keys, self)?.ok_or(Error::InvalidKey)?;
+        file.seek(io::SeekFrom::Start(0))?;
+        self.open_journaled(path, file, Key::from(key))
+            .map(|archive| (archive, key))
+    }
+
+    /// Opens the archive at the given path for reading only. As an interrupted
+    /// operation can't be rolled back without write access, this fails if the
+    /// archive has a journal describing one. Journaling has no effect.
+    pub fn open_readonly<P: AsRef<Path>, K: AsRef<[u8]>>(
+        &self,
+        path: P,
+        key: K,
+    ) -> Pk2Result<Pk2<ReadOnly>> {
+        self.open_readonly_with_key(path, Key::from(key.as_ref()))
+    }
+
+    /// Like [`OpenOptions::open_readonly`], but takes a [`Key`] which allows
+    /// for custom salts and raw keys.
+    pub fn open_readonly_with_key<P: AsRef<Path>>(
+        &self,
+        path: P,
+        key: Key<'_>,
+    ) -> Pk2Result<Pk2<ReadOnly>> {
+        self.validate()?;
+        let path = path.as_ref();
+        let file = stdfs::File::open(path)?;
+        if Journal::is_pending(path)? {
+            return Err(Error::Io(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "archive has an interrupted operation that has to be rolled back first",
+            )));
+        }
+        Pk2::_open_in_impl(ReadOnly::new(file), key, self)
+    }
+
+    /// Creates a new archive at the given path, failing if it already exists.
+    pub fn create_new<P: AsRef<Path>, K: AsRef<[u8]>>(&self, path: P, key: K) -> Pk2Result<Pk2> {
+        self.create_new_with_key(path, Key::from(key.as_ref()))
+    }
+
+    /// Like [`OpenOptions::create_new`], but takes a [`Key`] which allows for
+    /// custom salts and raw keys.
+    pub fn create_new_with_key<P: AsRef<Path>>(&self, path: P, key: Key<'_>) -> Pk2Result<Pk2> {
+        self.validate()?;
+        let path = path.as_ref();
+        let file = stdfs::OpenOptions::new()
+            .create_new(true)
+            .write(true)
+            .read(true)
+            .open(path)?;
+        let journal = self.create_journal(path, &file)?;
+        let mut archive = Pk2::_create_impl(file, key, self)?;
+        archive.file.journal = journal;
+        Ok(archive)
+    }
+
+    pub fn open_in<B, K>(&self, file: B, key: K) -> Pk2Result<Pk2<B>>
+    where
+        B: io::Read + io::Seek,
+        K: AsRef<[u8]>,
+    {
+        self.open_in_with_key(file, Key::from(key.as_ref()))
+    }
+
+    /// Like [`OpenOptions::open_in`], but takes a [`Key`] which allows for
+    /// custom salts and raw keys.
+    pub fn open_in_with_key<B>(&self, mut file: B, key: Key<'_>) -> Pk2Result<Pk2<B>>
+    where
+        B: io::Read + io::Seek,
+    {
+        self.validate()?;
+        file.seek(io::SeekFrom::Start(0))?;
+        Pk2::_open_in_impl(file, key, self)
+    }
+
+    pub fn create_new_in<B, K>(&self, file: B, key: K) -> Pk2Result<Pk2<B>>
+    where
+        B: io::Read + io::Write + io::Seek,
+        K: AsRef<[u8]>,
+    {
+        self.create_new_in_with_key(file, Key::from(key.as_ref()))
+    }
+
+    /// Like [`OpenOptions::create_new_in`], but takes a [`Key`] which allows
+    /// for custom salts and raw keys.
+    pub fn create_new_in_with_key<B>(&self, mut file: B, key: Key<'_>) -> Pk2Result<Pk2<B>>
+    where
+        B: io::Read + io::Write + io::Seek,
+    {
+        self.validate()?;
+        file.seek(io::SeekFrom::Start(0))?;
+        Pk2::_create_impl(file, key, self)
+    }
+
+    /// Fails if archives can't be opened or created with these options.
+    pub(super) fn validate(&self) -> Pk2Result<()> {
+        if !self.encoding.is_supported() {
+            return Err(Error::UnsupportedEncoding);
+        }
+        if self.invalid_signature {
+            return Err(Error::InvalidSignature);
+        }
+        Ok(())
+    }
+
+    /// Opens the archive at `path` for writing, rolling back an interrupted
+    /// operation if its journal describes one.
+    fn open_recovered(path: &Path) -> io::Result<stdfs::File> {
+        use std::io::Seek;
+        let mut file = stdfs::OpenOptions::new()
+            .write(true)
+            .read(true)
+            .open(path)?;
+        Journal::recover(path, &mut file)?;
+        file.seek(io::SeekFrom::Start(0))?;
+        Ok(file)
+    }
+
+    fn open_journaled(&self, path: &Path, file: stdfs::File, key: Key<'_>) -> Pk2Result<Pk2> {
+        let journal = self.create_journal(path, &file)?;
+        let mut archive = Pk2::_open_in_impl(file, key, self)?;
+        archive.file.journal = journal;
+        Ok(archive)
+    }
+
+    fn create_journal(&self, path: &Path, file: &stdfs::File) -> io::Result<Option<Journal>> {
+        if self.journal {
+            Journal::create(path, file).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+fn pad_signature(signature: &[u8]) -> Option<[u8; 30]> {
+    let mut padded = [0; 30];
+    padded
+        .get_mut(..signature.len())?
+        .copy_from_slice(signature);
+    Some(padded)
+}
@@ -0,0 +1,350 @@
+//! Repairing of damaged archives.
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::io::{Read, Seek, Write};
+use std::path::PathBuf;
+
+use super::Pk2;
+use crate::constants::PK2_ROOT_BLOCK;
+use crate::error::Pk2Result;
+use crate::raw::block_manager::LoadRepair;
+use crate::raw::entry::PackEntry;
+use crate::raw::ChainIndex;
+
+/// The result of [`Pk2::repair`], listing every change made to the archive.
+#[derive(Debug, Default)]
+pub struct RepairLog {
+    pub repairs: Vec<Repair>,
+}
+
+impl RepairLog {
+    /// Whether the archive was left untouched.
+    pub fn is_empty(&self) -> bool {
+        self.repairs.is_empty()
+    }
+}
+
+/// A change made while repairing an archive. Paths refer to the entry that
+/// has been changed, for blocks this is the directory they belong to.
+#[derive(Debug)]
+pub enum Repair {
+    /// The link from a block to the next block of its chain has been removed,
+    /// as it formed a cycle or pointed at a block that couldn't be read.
+    UnlinkedBlock { path: PathBuf, block: u64 },
+    /// An entry of an unknown type has been cleared.
+    ClearedUnknownEntry { path: PathBuf, index: usize },
+    /// A directory whose chain couldn't be read has been removed.
+    DroppedDirectory { path: PathBuf, chain: u64 },
+    /// A directory whose chain already belongs to another directory or to
+    /// one of its own ancestors has been removed.
+    DroppedSharedDirectory { path: PathBuf, chain: u64 },
+    /// A file whose data lies outside of the archive has been removed.
+    DroppedFile {
+        path: PathBuf,
+        offset: u64,
+        size: u32,
+    },
+    /// The `.` entry of a directory has been pointed at the directory itself.
+    FixedCurrentLink { path: PathBuf, from: u64, to: u64 },
+    /// The `..` entry of a directory has been pointed at its parent.
+    FixedParentLink { path: PathBuf, from: u64, to: u64 },
+}
+
+impl fmt::Display for Repair {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Repair::UnlinkedBlock { path, block } => write!(
+                f,
+                "{}: removed the broken link to the next block of the block at {:#x}",
+                path.display(),
+                block
+            ),
+            Repair::ClearedUnknownEntry { path, index } => write!(
+                f,
+                "{}: cleared entry {} of unknown type",
+                path.display(),
+                index
+            ),
+            Repair::DroppedDirectory { path, chain } => write!(
+                f,
+                "{}: removed directory with unreadable chain at {:#x}",
+                path.display(),
+                chain
+            ),
+            Repair::DroppedSharedDirectory { path, chain } => write!(
+                f,
+                "{}: removed directory with chain at {:#x} that is in use by another directory",
+                path.display(),
+                chain
+            ),
+            Repair::DroppedFile { path, offset, size } => write!(
+                f,
+                "{}: removed file with data at {:#x} and size {} outside of the archive",
+                path.display(),
+                offset,
+                size
+            ),
+            Repair::FixedCurrentLink { path, from, to } => write!(
+                f,
+                "{}: pointed `.` at {:#x} instead of {:#x}",
+                path.display(),
+                to,
+                from
+            ),
+            Repair::FixedParentLink { path, from, to } => write!(
+                f,
+                "{}: pointed `..` at {:#x} instead of {:#x}",
+                path.display(),
+                to,
+                from
+            ),
+        }
+    }
+}
+
+enum Fix {
+    Clear,
+    Link(ChainIndex),
+}
+
+impl<B> Pk2<B>
+where
+    B: Read + Write + Seek,
+{
+    /// Repairs the index of the archive in place so that it can be used
+    /// again, returning a log of all changes. The damage worked around while
+    /// opening the archive with [`OpenOptions::tolerant`] gets written out,
+    /// files whose data lies outside of the archive, directories whose
+    /// chains can't be read and directories sharing their chain with another
+    /// directory are removed and broken `.` and `..` links are fixed.
+    ///
+    /// [`OpenOptions::tolerant`]: super::OpenOptions::tolerant
+    pub fn repair(&mut self) -> Pk2Result<RepairLog> {
+        self.file.begin()?;
+        self.load_all()?;
+        let file_len = crate::io::file_len(self.file.get_mut())?;
+        let mut load_repairs: HashMap<ChainIndex, Vec<LoadRepair>> = HashMap::new();
+        for repair in self.block_manager.take_repairs() {
+            let chain = match repair {
+                LoadRepair::UnlinkedBlock { chain, .. }
+                | LoadRepair::ReplacedEntry { chain, .. }
+                | LoadRepair::UnreadableChain { chain } => chain,
+            };
+            load_repairs.entry(chain).or_default().push(repair);
+        }
+
+        let mut log = RepairLog::default();
+        let mut visited = HashSet::new();
+        visited.insert(PK2_ROOT_BLOCK);
+        // (path, chain, parent chain)
+        let mut stack = vec![(PathBuf::from("/"), PK2_ROOT_BLOCK, PK2_ROOT_BLOCK)];
+        while let Some((path, chain_index, parent)) = stack.pop() {
+            let mut dirty = false;
+            for repair in load_repairs.remove(&chain_index).unwrap_or_default() {
+                dirty = true;
+                log.repairs.push(match repair {
+                    LoadRepair::UnlinkedBlock { block, .. } => Repair::UnlinkedBlock {
+                        path: path.clone(),
+                        block: block.0,
+                    },
+                    LoadRepair::ReplacedEntry { index, .. } => Repair::ClearedUnknownEntry {
+                        path: path.clone(),
+                        index,
+                    },
+                    // handled by the parent directory
+                    LoadRepair::UnreadableChain { .. } => continue,
+                });
+            }
+            let chain = match self.block_manager.get(chain_index) {
+                Some(chain) => chain,
+                None => continue,
+            };
+            let mut fixes = Vec::new();
+            for (idx, entry) in chain.entries().enumerate() {
+                match entry {
+                    PackEntry::File(file) => {
+                        let (offset, size) = (file.pos_data(), file.size());
+                        if offset.saturating_add(size as u64) > file_len {
+                            log.repairs.push(Repair::DroppedFile {
+                                path: path.join(file.name()),
+                                offset,
+                                size,
+                            });
+                            fixes.push((idx, Fix::Clear));
+                        }
+                    }
+                    PackEntry::Directory(dir) if dir.is_current_link() => {
+                        let from = dir.children_position();
+                        if from != chain_index {
+                            log.repairs.push(Repair::FixedCurrentLink {
+                                path: path.clone(),
+                                from: from.0,
+                                to: chain_index.0,
+                            });
+                            fixes.push((idx, Fix::Link(chain_index)));
+                        }
+                    }
+                    PackEntry::Directory(dir) if dir.is_parent_link() => {
+                        let from = dir.children_position();
+                        if from != parent {
+                            log.repairs.push(Repair::FixedParentLink {
+                                path: path.clone(),
+                                from: from.0,
+                                to: parent.0,
+                            });
+                            fixes.push((idx, Fix::Link(parent)));
+                        }
+                    }
+                    PackEntry::Directory(dir) => {
+                        let dir_path = path.join(dir.name());
+                        let children = dir.children_position();
+                        if self.block_manager.get(children).is_none() {
+                            log.repairs.push(Repair::DroppedDirectory {
+                                path: dir_path,
+                                chain: children.0,
+                            });
+                            fixes.push((idx, Fix::Clear));
+                        } else if visited.insert(children) {
+                            stack.push((dir_path, children, chain_index));
+                        } else {
+                            log.repairs.push(Repair::DroppedSharedDirectory {
+                                path: dir_path,
+                                chain: children.0,
+                            });
+                            fixes.push((idx, Fix::Clear));
+                        }
+                    }
+                    PackEntry::Empty(_) => (),
+                }
+            }
+            if fixes.is_empty() && !dirty {
+                continue;
+            }
+
+            let chain = self.block_manager.get_mut(chain_index).unwrap();
+            for (idx, fix) in fixes {
+                match fix {
+                    Fix::Clear => chain.clear_entry(idx),
+                    Fix::Link(target) => {
+                        if let Some(dir) = chain.get_mut(idx).and_then(PackEntry::as_directory_mut)
+                        {
+                            dir.set_children_position(target);
+                        }
+                    }
+                }
+            }
+            for (offset, block) in chain.blocks() {
+                crate::io::write_block(
+                    self.blowfish.as_ref(),
+                    self.encoding,
+                    self.file.get_mut(),
+                    offset,
+                    block,
+                )?;
+            }
+        }
+
+        self.free_list = Self::compute_free_list(&self.block_manager, self.file.get_mut())?;
+        self.file.commit()?;
+        Ok(log)
+    }
+}
+
+/// The paths of a damaged archive, the damage and whether the repairs made found
+/// are the expected ones.
+#[cfg(test)]
+type RepairCase = (
+    &'static [&'static str],
+    super::Damage,
+    fn(&[Repair]) -> bool,
+);
+
+#[test]
+fn test_repair_damage() {
+    let cases: &[RepairCase] = &[
+        (
+            &["/a/file", "/b/file"],
+            |_, buf| super::patch_entry(buf, 1, "a", 106, &(1u64 << 40).to_le_bytes()),
+            |repairs| {
+                matches!(
+                    repairs,
+                    [Repair::DroppedDirectory { path, chain }]
+                        if path.ends_with("a") && *chain == 1 << 40
+                )
+            },
+        ),
+        (
+            &["/dir/file", "/other"],
+            |_, buf| super::patch_entry(buf, 2, "file", 106, &(1u64 << 40).to_le_bytes()),
+            |repairs| matches!(repairs, [Repair::DroppedFile { path, .. }] if path.ends_with("file")),
+        ),
+        (
+            &["/dir/file", "/other"],
+            |_, buf| super::patch_entry(buf, 2, "other", 0, &[7]),
+            |repairs| matches!(repairs, [Repair::ClearedUnknownEntry { .. }]),
+        ),
+        (
+            // a directory pointing back at the root
+            &["/a/b/file"],
+            |_, buf| super::patch_entry(buf, 1, "b", 106, &PK2_ROOT_BLOCK.0.to_le_bytes()),
+            |repairs| {
+                matches!(
+                    repairs,
+                    [Repair::DroppedSharedDirectory { path, .. }] if path.ends_with("a/b")
+                )
+            },
+        ),
+        (
+            // the last block of a chain linking back to its first block
+            &["/a/file"],
+            |archive, buf| {
+                let chain = super::chain_of(archive, "/a");
+                let last = chain as usize + 19 * 128;
+                buf[last + 118..][..8].copy_from_slice(&chain.to_le_bytes());
+            },
+            |repairs| matches!(repairs, [Repair::UnlinkedBlock { path, .. }] if path.ends_with("a")),
+        ),
+        (
+            &["/a/file", "/b/file"],
+            |archive, buf| {
+                let chain = super::chain_of(archive, "/a");
+                super::patch_entry(buf, 1, "b", 106, &chain.to_le_bytes())
+            },
+            |repairs| matches!(repairs, [Repair::DroppedSharedDirectory { .. }]),
+        ),
+        (
+            &["/a/file"],
+            |_, buf| {
+                super::patch_entry(buf, 1, ".", 106, &0x1234u64.to_le_bytes());
+                super::patch_entry(buf, 1, "..", 106, &0x5678u64.to_le_bytes());
+            },
+            |repairs| {
+                repairs.len() == 2
+                    && repairs.iter().any(|repair| {
+                        matches!(repair, Repair::FixedCurrentLink { from: 0x1234, .. })
+                    })
+                    && repairs.iter().any(|repair| {
+                        matches!(
+                            repair,
+                            Repair::FixedParentLink { path, from: 0x5678, to }
+                                if path.ends_with("a") && *to == PK2_ROOT_BLOCK.0
+                        )
+                    })
+            },
+        ),
+    ];
+    for (i, &(paths, damage, expected)) in cases.iter().enumerate() {
+        let mut buf = super::damaged_archive(paths, damage);
+        let mut archive = super::OpenOptions::new()
+            .tolerant(true)
+            .open_in(&mut buf, "")
+            .unwrap();
+        let log = archive.repair().unwrap();
+        assert!(expected(&log.repairs), "case {}: {:?}", i, log.repairs);
+        assert!(archive.check().unwrap().is_ok(), "case {}", i);
+        drop(archive);
+        // the repaired archive opens without being tolerant
+        let archive = Pk2::open_in(&mut buf, "").unwrap();
+        assert!(archive.check().unwrap().is_ok(), "case {}", i);
+    }
+}
@@ -27,15 +27,71 @@ fn next_u32_wrap(buf: &[u8], offset: &mut usize) -> u32 {
     v
 }
 
+/// The key an archive is encrypted with. An empty key stands for an
+/// unencrypted archive.
+///
+/// Plain byte strings convert into a key salted with [`PK2_SALT`] like the
+/// official clients do.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Key<'a> {
+    /// A user key that gets combined with the salt before use.
+    Salted { key: &'a [u8], salt: &'a [u8] },
+    /// An already derived key that is used as is.
+    Raw(&'a [u8]),
+}
+
+impl<'a> Key<'a> {
+    /// Whether this key stands for an unencrypted archive.
+    pub fn is_empty(&self) -> bool {
+        match self {
+            Key::Salted { key, .. } | Key::Raw(key) => key.is_empty(),
+        }
+    }
+
+    /// Creates the cipher for this key, or `None` if the key is empty.
+    pub fn blowfish(&self) -> Pk2Result<Option<Blowfish>> {
+        match *self {
+            _ if self.is_empty() => Ok(None),
+            Key::Salted { key, salt } => Blowfish::new_salted(key, salt).map(Some),
+            Key::Raw(key) => Blowfish::new_raw(key).map(Some),
+        }
+    }
+}
+
+impl<'a, K: AsRef<[u8]> + ?Sized> From<&'a K> for Key<'a> {
+    fn from(key: &'a K) -> Self {
+        Key::Salted {
+            key: key.as_ref(),
+            salt: &PK2_SALT,
+        }
+    }
+}
+
 impl Blowfish {
+    /// Creates the cipher for a user key salted with [`PK2_SALT`].
     pub fn new(key: &[u8]) -> Pk2Result<Self> {
-        if key.len() < 4 || key.len() > 56 {
+        Self::new_salted(key, &PK2_SALT)
+    }
+
+    /// Creates the cipher for a user key combined with a custom salt, as used
+    /// by some modified clients. The salt may be at most 56 bytes long.
+    pub fn new_salted(key: &[u8], salt: &[u8]) -> Pk2Result<Self> {
+        if salt.len() > 56 {
             return Err(Error::InvalidKey);
         }
         let mut key = key.to_vec();
-        gen_final_blowfish_key_inplace(&mut key);
+        gen_final_blowfish_key_inplace(&mut key, salt);
+        Self::new_raw(&key)
+    }
+
+    /// Creates the cipher for an already derived key without applying any
+    /// salt, so that it can be used for other files encrypted the same way.
+    pub fn new_raw(key: &[u8]) -> Pk2Result<Self> {
+        if key.len() < 4 || key.len() > 56 {
+            return Err(Error::InvalidKey);
+        }
         let mut this = Blowfish { p: P, s: S };
-        this.expand_key(&key);
+        this.expand_key(key);
         Ok(this)
     }
 
@@ -121,11 +177,11 @@ impl Blowfish {
     }
 }
 
-fn gen_final_blowfish_key_inplace(key: &mut [u8]) {
+fn gen_final_blowfish_key_inplace(key: &mut [u8], salt: &[u8]) {
     let key_len = key.len().min(56);
 
     let mut base_key = [0; 56];
-    base_key[0..PK2_SALT.len()].copy_from_slice(&PK2_SALT);
+    base_key[0..salt.len()].copy_from_slice(salt);
 
     for i in 0..key_len {
         key[i] ^= base_key[i];
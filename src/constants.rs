@@ -10,6 +10,15 @@ pub const PK2_SIGNATURE: &[u8; 30] =
 pub const PK2_SALT: [u8; 10] = [0x03, 0xF8, 0xE4, 0x44, 0x88, 0x99, 0x3F, 0x64, 0xFE, 0x35];
 pub const PK2_CHECKSUM_STORED: usize = 3;
 pub const PK2_CHECKSUM: &[u8; 16] = b"Joymax Pak File\0";
+/// Blowfish keys used by known clients as (regions, key) pairs, each key
+/// listed once alongside every region whose client uses it. These are the
+/// only keys known so far, other clients are expected to reuse one of them.
+pub const PK2_KNOWN_KEYS: &[(&[&str], &[u8])] = &[
+    // international client, which most private servers reuse as well
+    (&["isro", "vsro", "ksro"], b"169841"),
+    // chinese client, "2009年" in GBK
+    (&["csro"], b"2009\xC4\xEA"),
+];
 
 pub const PK2_FILE_ENTRY_SIZE: usize = mem::size_of::<RawPackFileEntry>();
 pub const PK2_FILE_BLOCK_ENTRY_COUNT: usize = 20;
@@ -20,6 +29,9 @@ pub const PK2_ROOT_BLOCK: ChainIndex = ChainIndex(mem::size_of::<RawPackHeader>(
 // Sentinel entry to give the root block a proper path descriptor
 pub const PK2_ROOT_BLOCK_VIRTUAL: ChainIndex = ChainIndex(0 as u64);
 
+// The name field holds 81 bytes including the terminating NUL
+pub const PK2_FILE_NAME_MAX_LEN: usize = 80;
+
 pub static PK2_CURRENT_DIR_IDENT: &str = ".";
 pub static PK2_PARENT_DIR_IDENT: &str = "..";
 
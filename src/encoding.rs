@@ -0,0 +1,135 @@
+use std::borrow::Cow;
+use std::fmt;
+
+/// The encoding entry names of an archive are stored in. Which one is used
+/// depends on the region of the client the archive belongs to.
+///
+/// Without the `euc` feature only [`Encoding::Utf8`] is supported, opening or
+/// creating an archive with any other encoding fails with
+/// [`Error::UnsupportedEncoding`](crate::Error::UnsupportedEncoding).
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Encoding {
+    /// Korean, used by the korean and international clients.
+    EucKr,
+    /// Simplified chinese, used by the chinese clients.
+    Gbk,
+    /// Japanese, used by the japanese clients.
+    ShiftJis,
+    /// Traditional chinese, used by the taiwanese clients.
+    Big5,
+    /// Turkish, used by the turkish clients.
+    Windows1254,
+    /// Vietnamese, used by the vietnamese clients.
+    Windows1258,
+    /// UTF-8, invalid sequences are replaced when decoding.
+    Utf8,
+}
+
+impl Encoding {
+    /// All encodings, including the ones that aren't supported without the
+    /// `euc` feature.
+    pub const ALL: &'static [Encoding] = &[
+        Encoding::EucKr,
+        Encoding::Gbk,
+        Encoding::ShiftJis,
+        Encoding::Big5,
+        Encoding::Windows1254,
+        Encoding::Windows1258,
+        Encoding::Utf8,
+    ];
+
+    /// The name of the encoding as accepted by [`Encoding::from_name`].
+    pub fn name(self) -> &'static str {
+        match self {
+            Encoding::EucKr => "euc-kr",
+            Encoding::Gbk => "gbk",
+            Encoding::ShiftJis => "shift-jis",
+            Encoding::Big5 => "big5",
+            Encoding::Windows1254 => "windows-1254",
+            Encoding::Windows1258 => "windows-1258",
+            Encoding::Utf8 => "utf-8",
+        }
+    }
+
+    /// Looks up an encoding by its name, ignoring ASCII case.
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL
+            .iter()
+            .copied()
+            .find(|encoding| encoding.name().eq_ignore_ascii_case(name))
+    }
+
+    /// Whether names can be decoded and encoded with this encoding, which is
+    /// only the case for [`Encoding::Utf8`] without the `euc` feature.
+    pub fn is_supported(self) -> bool {
+        cfg!(feature = "euc") || self == Encoding::Utf8
+    }
+
+    /// Decodes a name, replacing invalid sequences.
+    pub fn decode(self, bytes: &[u8]) -> String {
+        match self.backend() {
+            #[cfg(feature = "euc")]
+            Some(encoding) => encoding.decode_without_bom_handling(bytes).0.into_owned(),
+            _ => String::from_utf8_lossy(bytes).into_owned(),
+        }
+    }
+
+    /// Encodes a name. Characters that can't be represented are replaced by
+    /// HTML numeric character references.
+    pub fn encode(self, name: &str) -> Cow<'_, [u8]> {
+        match self.backend() {
+            #[cfg(feature = "euc")]
+            Some(encoding) => encoding.encode(name).0,
+            _ => Cow::Borrowed(name.as_bytes()),
+        }
+    }
+
+    /// Encodes a name, returning `None` if it contains characters that can't
+    /// be represented.
+    pub fn try_encode(self, name: &str) -> Option<Cow<'_, [u8]>> {
+        match self.backend() {
+            #[cfg(feature = "euc")]
+            Some(encoding) => match encoding.encode(name) {
+                (_, _, true) => None,
+                (encoded, _, false) => Some(encoded),
+            },
+            _ => Some(Cow::Borrowed(name.as_bytes())),
+        }
+    }
+
+    #[cfg(feature = "euc")]
+    fn backend(self) -> Option<&'static encoding_rs::Encoding> {
+        match self {
+            Encoding::EucKr => Some(encoding_rs::EUC_KR),
+            Encoding::Gbk => Some(encoding_rs::GBK),
+            Encoding::ShiftJis => Some(encoding_rs::SHIFT_JIS),
+            Encoding::Big5 => Some(encoding_rs::BIG5),
+            Encoding::Windows1254 => Some(encoding_rs::WINDOWS_1254),
+            Encoding::Windows1258 => Some(encoding_rs::WINDOWS_1258),
+            Encoding::Utf8 => None,
+        }
+    }
+
+    #[cfg(not(feature = "euc"))]
+    fn backend(self) -> Option<()> {
+        None
+    }
+}
+
+#[cfg(feature = "euc")]
+const DEFAULT_ENCODING: Encoding = Encoding::EucKr;
+#[cfg(not(feature = "euc"))]
+const DEFAULT_ENCODING: Encoding = Encoding::Utf8;
+
+impl Default for Encoding {
+    /// EUC-KR, or UTF-8 without the `euc` feature.
+    fn default() -> Self {
+        DEFAULT_ENCODING
+    }
+}
+
+impl fmt::Display for Encoding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
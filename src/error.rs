@@ -15,6 +15,12 @@ pub enum Error {
     UnsupportedVersion,
     NotFound,
     AlreadyExists,
+    DirectoryNotEmpty,
+    InvalidName,
+    InvalidMetadata,
+    UnsupportedEncoding,
+    InvalidSignature,
+    UnsupportedMetadataVersion,
 }
 
 impl std::error::Error for Error {}
@@ -34,6 +40,21 @@ impl fmt::Display for Error {
             }
             Error::NotFound => write!(f, "file or directory not found"),
             Error::AlreadyExists => write!(f, "path already exists"),
+            Error::DirectoryNotEmpty => write!(f, "directory is not empty"),
+            Error::InvalidName => write!(
+                f,
+                "name is too long, contains invalid characters or can't be encoded"
+            ),
+            Error::InvalidMetadata => {
+                write!(f, "metadata is malformed or doesn't fit into the header")
+            }
+            Error::UnsupportedEncoding => {
+                write!(f, "encoding is not supported without the `euc` feature")
+            }
+            Error::InvalidSignature => write!(f, "signature is longer than 30 bytes"),
+            Error::UnsupportedMetadataVersion => {
+                write!(f, "metadata layout version is not supported")
+            }
         }
     }
 }
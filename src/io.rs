@@ -1,16 +1,19 @@
 use std::io;
 
+use crate::archive::FileTimes;
 use crate::constants::{
     PK2_CURRENT_DIR_IDENT, PK2_FILE_BLOCK_SIZE, PK2_FILE_ENTRY_SIZE, PK2_PARENT_DIR_IDENT,
 };
 use crate::error::Pk2Result;
 use crate::raw::block_chain::{PackBlock, PackBlockChain};
 use crate::raw::entry::PackEntry;
+use crate::raw::free_list::FreeList;
 use crate::raw::{BlockOffset, ChainIndex, EntryOffset};
-use crate::Blowfish;
+use crate::{Blowfish, Encoding};
 
 pub fn read_block_at<F: io::Seek + io::Read>(
     bf: Option<&Blowfish>,
+    encoding: Encoding,
     mut file: F,
     BlockOffset(offset): BlockOffset,
 ) -> Pk2Result<PackBlock> {
@@ -18,30 +21,62 @@ pub fn read_block_at<F: io::Seek + io::Read>(
     file.seek(io::SeekFrom::Start(offset))?;
     file.read_exact(&mut buf)?;
     bf.map(|bf| bf.decrypt(&mut buf));
-    PackBlock::from_reader(&buf[..])
+    PackBlock::from_reader(&buf[..], encoding)
+}
+
+/// Reads a block like [`read_block_at`], replacing entries of an unknown type
+/// with empty ones, see [`PackBlock::from_bytes_tolerant`].
+pub fn read_block_at_tolerant<F: io::Seek + io::Read>(
+    bf: Option<&Blowfish>,
+    encoding: Encoding,
+    mut file: F,
+    BlockOffset(offset): BlockOffset,
+) -> Pk2Result<(PackBlock, Vec<usize>)> {
+    let mut buf = [0; PK2_FILE_BLOCK_SIZE];
+    file.seek(io::SeekFrom::Start(offset))?;
+    file.read_exact(&mut buf)?;
+    if let Some(bf) = bf {
+        bf.decrypt(&mut buf);
+    }
+    PackBlock::from_bytes_tolerant(&buf, encoding)
 }
 
 pub fn file_len<F: io::Seek>(mut file: F) -> io::Result<u64> {
     file.seek(io::SeekFrom::End(0))
 }
 
+/// Returns the offset of a free region that can hold a block, or the end of
+/// the file if there is none.
+fn allocate_block_offset<F: io::Seek>(
+    free_list: &mut FreeList,
+    file: F,
+) -> io::Result<BlockOffset> {
+    match free_list.allocate(PK2_FILE_BLOCK_SIZE as u64) {
+        Some(offset) => Ok(BlockOffset(offset)),
+        None => file_len(file).map(BlockOffset),
+    }
+}
+
 pub fn allocate_empty_block<F: io::Seek + io::Write>(
     bf: Option<&Blowfish>,
+    encoding: Encoding,
+    free_list: &mut FreeList,
     mut file: F,
 ) -> Pk2Result<(BlockOffset, PackBlock)> {
-    let offset = file_len(&mut file).map(crate::raw::BlockOffset)?;
+    let offset = allocate_block_offset(free_list, &mut file)?;
     let block = PackBlock::default();
-    write_block(bf, file, offset, &block).map(|_| (offset, block))
+    write_block(bf, encoding, file, offset, &block).map(|_| (offset, block))
 }
 
 pub fn write_block<F: io::Seek + io::Write>(
     bf: Option<&Blowfish>,
+    encoding: Encoding,
     mut file: F,
     BlockOffset(offset): BlockOffset,
     block: &PackBlock,
 ) -> Pk2Result<()> {
     let mut buf = [0; PK2_FILE_BLOCK_SIZE];
-    block.to_writer(&mut buf[..])?;
+    block.to_writer(&mut buf[..], encoding)?;
     bf.map(|bf| bf.encrypt(&mut buf));
     file.seek(io::SeekFrom::Start(offset))?;
     file.write_all(&buf)?;
@@ -50,12 +85,13 @@ pub fn write_block<F: io::Seek + io::Write>(
 
 pub fn write_entry_at<F: io::Seek + io::Write>(
     bf: Option<&Blowfish>,
+    encoding: Encoding,
     mut file: F,
     EntryOffset(offset): EntryOffset,
     entry: &PackEntry,
 ) -> io::Result<()> {
     let mut buf = [0; PK2_FILE_ENTRY_SIZE];
-    entry.to_writer(&mut buf[..])?;
+    entry.to_writer(&mut buf[..], encoding)?;
     bf.map(|bf| bf.encrypt(&mut buf));
     file.seek(io::SeekFrom::Start(offset))?;
     file.write_all(&buf)?;
@@ -64,24 +100,34 @@ pub fn write_entry_at<F: io::Seek + io::Write>(
 
 pub fn write_chain_entry<F: io::Seek + io::Write>(
     bf: Option<&Blowfish>,
+    encoding: Encoding,
     file: F,
     chain: &PackBlockChain,
     entry_index: usize,
 ) -> io::Result<()> {
     write_entry_at(
         bf,
+        encoding,
         file,
         chain.file_offset_for_entry(entry_index).unwrap(),
         &chain[entry_index],
     )
 }
 
-/// Write data to the end of the file returning the offset of the written
-/// data in the file.
-pub fn write_new_data_buffer<F: io::Seek + io::Write>(mut file: F, data: &[u8]) -> io::Result<u64> {
-    let file_end = file.seek(io::SeekFrom::End(0))?;
+/// Write data into a free region of the file, or to the end of the file if
+/// no free region is big enough, returning the offset of the written data in
+/// the file.
+pub fn write_new_data_buffer<F: io::Seek + io::Write>(
+    free_list: &mut FreeList,
+    mut file: F,
+    data: &[u8],
+) -> io::Result<u64> {
+    let offset = match free_list.allocate(data.len() as u64) {
+        Some(offset) => file.seek(io::SeekFrom::Start(offset))?,
+        None => file.seek(io::SeekFrom::End(0))?,
+    };
     file.write_all(data)?;
-    Ok(file_end)
+    Ok(offset)
 }
 
 pub fn write_data_buffer_at<F: io::Seek + io::Write>(
@@ -93,24 +139,45 @@ pub fn write_data_buffer_at<F: io::Seek + io::Write>(
     file.write_all(data)
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn allocate_new_block_chain<F: io::Seek + io::Write>(
     blowfish: Option<&Blowfish>,
+    encoding: Encoding,
+    free_list: &mut FreeList,
     mut file: F,
     current_chain: &mut PackBlockChain,
     dir_name: &str,
     chain_entry_idx: usize,
+    times: FileTimes,
 ) -> Pk2Result<PackBlockChain> {
-    let new_chain_offset = crate::io::file_len(&mut file).map(ChainIndex)?;
-    let entry = &mut current_chain[chain_entry_idx];
-    *entry = PackEntry::new_directory(dir_name, new_chain_offset, entry.next_block());
+    let BlockOffset(new_chain_offset) = allocate_block_offset(free_list, &mut file)?;
+    let new_chain_offset = ChainIndex(new_chain_offset);
+    let next_block = current_chain[chain_entry_idx].next_block();
+    let mut entry = PackEntry::new_directory(dir_name, new_chain_offset, next_block);
+    times.apply(&mut entry);
+    current_chain.set_entry(chain_entry_idx, entry);
     let offset = current_chain
         .file_offset_for_entry(chain_entry_idx)
         .unwrap();
     let mut block = PackBlock::default();
     block[0] = PackEntry::new_directory(PK2_CURRENT_DIR_IDENT, new_chain_offset, None);
     block[1] = PackEntry::new_directory(PK2_PARENT_DIR_IDENT, current_chain.chain_index(), None);
-    write_block(blowfish, &mut file, new_chain_offset.into(), &block)?;
-    write_entry_at(blowfish, file, offset, &current_chain[chain_entry_idx])?;
+    times.apply(&mut block[0]);
+    times.apply(&mut block[1]);
+    write_block(
+        blowfish,
+        encoding,
+        &mut file,
+        new_chain_offset.into(),
+        &block,
+    )?;
+    write_entry_at(
+        blowfish,
+        encoding,
+        file,
+        offset,
+        &current_chain[chain_entry_idx],
+    )?;
     Ok(PackBlockChain::from_blocks(vec![(
         new_chain_offset.into(),
         block,
@@ -12,5 +12,8 @@ pub use self::error::Error;
 mod filetime;
 pub(crate) use self::filetime::FILETIME;
 
+mod encoding;
+pub use self::encoding::Encoding;
+
 mod blowfish;
-pub use self::blowfish::Blowfish;
+pub use self::blowfish::{Blowfish, Key};
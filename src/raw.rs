@@ -1,6 +1,7 @@
 pub mod block_chain;
 pub mod block_manager;
 pub mod entry;
+pub mod free_list;
 pub mod header;
 
 #[derive(Copy, Clone, Debug, Hash, PartialEq, Eq, PartialOrd, Ord)]
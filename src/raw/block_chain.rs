@@ -1,40 +1,119 @@
+use byteorder::{ReadBytesExt, LE};
+
+use std::collections::HashMap;
 use std::io::{Read, Result as IoResult, Write};
+use std::num::NonZeroU64;
 use std::ops;
 
 use super::entry::{DirectoryEntry, PackEntry};
 use super::{BlockOffset, ChainIndex, EntryOffset};
 use crate::constants::*;
 use crate::error::{Error, Pk2Result};
-use crate::io::RawIo;
+use crate::Encoding;
 
 /// A collection of [`PackBlock`]s where each blocks next_block field points to
 /// the following block in the file. A PackBlockChain is never empty.
+///
+/// The chain keeps an index of the names of its entries to look them up
+/// without scanning every entry, so names of entries must only be changed
+/// through [`PackBlockChain::set_entry`], [`PackBlockChain::rename_entry`] and
+/// [`PackBlockChain::clear_entry`].
 pub struct PackBlockChain {
     // (offset, block)
     blocks: Vec<(BlockOffset, PackBlock)>,
+    // ascii lowercased name -> index of the first entry with that name
+    names: HashMap<String, usize>,
+    // whether multiple entries share a name, only happens in damaged archives
+    duplicates: bool,
 }
 
 #[allow(clippy::len_without_is_empty)]
 impl PackBlockChain {
-    #[inline]
     pub fn from_blocks(blocks: Vec<(BlockOffset, PackBlock)>) -> Self {
         debug_assert!(!blocks.is_empty());
-        PackBlockChain { blocks }
+        let mut chain = PackBlockChain {
+            blocks,
+            names: HashMap::new(),
+            duplicates: false,
+        };
+        for idx in 0..chain.num_entries() {
+            chain.index_name(idx);
+        }
+        chain
     }
 
-    #[inline]
     pub fn push_and_link(&mut self, offset: BlockOffset, block: PackBlock) {
         self.last_entry_mut().set_next_block(offset);
         self.blocks.push((offset, block));
+        for idx in self.num_entries() - PK2_FILE_BLOCK_ENTRY_COUNT..self.num_entries() {
+            self.index_name(idx);
+        }
     }
 
-    #[inline]
     pub fn pop_and_unlink(&mut self) {
+        assert!(self.blocks.len() > 1);
+        for idx in self.num_entries() - PK2_FILE_BLOCK_ENTRY_COUNT..self.num_entries() {
+            self.unindex_name(idx);
+        }
         self.blocks.pop();
-        assert!(!self.blocks.is_empty());
         self.last_entry_mut().set_next_block(BlockOffset(0));
     }
 
+    /// Replaces the entry at the given index, keeping the name index up to
+    /// date.
+    pub fn set_entry(&mut self, idx: usize, entry: PackEntry) {
+        self.unindex_name(idx);
+        *self.entry_mut(idx) = entry;
+        self.index_name(idx);
+    }
+
+    /// Renames the entry at the given index, keeping the name index up to
+    /// date. Does nothing if the entry is empty.
+    pub fn rename_entry(&mut self, idx: usize, name: String) {
+        self.unindex_name(idx);
+        self.entry_mut(idx).set_name(name);
+        self.index_name(idx);
+    }
+
+    /// Clears the entry at the given index, keeping the name index up to date.
+    pub fn clear_entry(&mut self, idx: usize) {
+        self.unindex_name(idx);
+        self.entry_mut(idx).clear();
+    }
+
+    fn index_name(&mut self, idx: usize) {
+        let name = match self[idx].name() {
+            Some(name) => name.to_ascii_lowercase(),
+            None => return,
+        };
+        let first = self.names.entry(name).or_insert(idx);
+        if *first != idx {
+            self.duplicates = true;
+            *first = idx.min(*first);
+        }
+    }
+
+    fn unindex_name(&mut self, idx: usize) {
+        let name = match self[idx].name() {
+            Some(name) => name.to_ascii_lowercase(),
+            None => return,
+        };
+        if self.names.get(&name) != Some(&idx) {
+            return;
+        }
+        self.names.remove(&name);
+        if self.duplicates {
+            let other = self
+                .entries()
+                .enumerate()
+                .find(|&(i, entry)| i != idx && entry.name_eq_ignore_ascii_case(&name))
+                .map(|(i, _)| i);
+            if let Some(other) = other {
+                self.names.insert(name, other);
+            }
+        }
+    }
+
     /// This blockchains chain index/file offset.
     /// Note: This is the same as its first block
     #[inline]
@@ -74,21 +153,47 @@ impl PackBlockChain {
 
     /// Returns the last entry of this PackBlockChain.
     #[inline]
-    pub fn last_entry_mut(&mut self) -> &mut PackEntry {
+    pub(crate) fn last_entry_mut(&mut self) -> &mut PackEntry {
         let last = self.num_entries() - 1;
-        &mut self[last]
+        self.entry_mut(last)
     }
 
-    /// An iterator over the entries of this chain.
-    pub fn entries(&self) -> impl Iterator<Item = &PackEntry> {
-        self.blocks.iter().flat_map(|block| &block.1.entries)
+    /// An iterator over the blocks of this chain and their file offsets.
+    pub fn blocks(&self) -> impl Iterator<Item = (BlockOffset, &PackBlock)> {
+        self.blocks.iter().map(|(offset, block)| (*offset, block))
+    }
+
+    /// Moves the blocks of this chain as well as every file offset its
+    /// entries refer to according to `relocate`.
+    pub fn relocate(&mut self, relocate: impl Fn(u64) -> u64) {
+        for (BlockOffset(offset), block) in &mut self.blocks {
+            *offset = relocate(*offset);
+            for entry in block.entries_mut() {
+                match entry {
+                    PackEntry::Directory(dir) => {
+                        let ChainIndex(pos_children) = dir.children_position();
+                        dir.set_children_position(ChainIndex(relocate(pos_children)))
+                    }
+                    PackEntry::File(file) if file.size != 0 => {
+                        file.pos_data = relocate(file.pos_data)
+                    }
+                    _ => (),
+                }
+                if let Some(next_block) = entry.next_block() {
+                    entry.set_next_block(BlockOffset(relocate(next_block.get())));
+                }
+            }
+        }
+    }
+
+    /// An iterator over the file offsets of the blocks in this chain.
+    pub fn block_offsets(&self) -> impl Iterator<Item = BlockOffset> + '_ {
+        self.blocks.iter().map(|&(offset, _)| offset)
     }
 
     /// An iterator over the entries of this chain.
-    pub fn entries_mut(&mut self) -> impl Iterator<Item = &mut PackEntry> {
-        self.blocks
-            .iter_mut()
-            .flat_map(|block| &mut block.1.entries)
+    pub fn entries(&self) -> impl Iterator<Item = &PackEntry> {
+        self.blocks.iter().flat_map(|block| &block.1.entries)
     }
 
     /// Get the PackEntry at the specified offset.
@@ -98,26 +203,41 @@ impl PackBlockChain {
             .and_then(|(_, block)| block.get(entry % PK2_FILE_BLOCK_ENTRY_COUNT))
     }
 
-    /// Get the PackEntry at the specified offset.
-    pub fn get_mut(&mut self, entry: usize) -> Option<&mut PackEntry> {
+    /// Get the PackEntry at the specified offset. The name of the entry must
+    /// not be changed through the returned reference, see [`PackBlockChain`].
+    pub(crate) fn get_mut(&mut self, entry: usize) -> Option<&mut PackEntry> {
         self.blocks
             .get_mut(entry / PK2_FILE_BLOCK_ENTRY_COUNT)
             .and_then(|(_, block)| block.get_mut(entry % PK2_FILE_BLOCK_ENTRY_COUNT))
     }
 
+    /// Looks up the entry with the given name ignoring ASCII case, returning
+    /// its index and the entry itself.
+    pub fn find_entry(&self, name: &str) -> Option<(usize, &PackEntry)> {
+        let idx = *self.names.get(&name.to_ascii_lowercase())?;
+        Some((idx, &self[idx]))
+    }
+
     /// Looks up the `directory` name in this [`PackBlockChain`], returning the
     /// offset of the ['PackBlockChain'] corresponding to the directory if
     /// successful.
     pub fn find_block_chain_index_of(&self, directory: &str) -> Pk2Result<ChainIndex> {
-        self.entries()
-            .find(|entry| entry.name_eq_ignore_ascii_case(directory))
+        self.find_entry(directory)
             .ok_or(Error::NotFound)?
+            .1
             .as_directory()
             .map(DirectoryEntry::children_position)
             .ok_or(Error::ExpectedDirectory)
     }
 }
 
+impl PackBlockChain {
+    #[inline]
+    fn entry_mut(&mut self, idx: usize) -> &mut PackEntry {
+        &mut self.blocks[idx / PK2_FILE_BLOCK_ENTRY_COUNT].1[idx % PK2_FILE_BLOCK_ENTRY_COUNT]
+    }
+}
+
 impl ops::Index<usize> for PackBlockChain {
     type Output = PackEntry;
     fn index(&self, idx: usize) -> &Self::Output {
@@ -125,12 +245,6 @@ impl ops::Index<usize> for PackBlockChain {
     }
 }
 
-impl ops::IndexMut<usize> for PackBlockChain {
-    fn index_mut(&mut self, idx: usize) -> &mut Self::Output {
-        &mut self.blocks[idx / PK2_FILE_BLOCK_ENTRY_COUNT].1[idx % PK2_FILE_BLOCK_ENTRY_COUNT]
-    }
-}
-
 /// A collection of 20 [`PackEntry`]s.
 #[derive(Default)]
 pub struct PackBlock {
@@ -157,21 +271,47 @@ impl PackBlock {
     pub fn get_mut(&mut self, entry: usize) -> Option<&mut PackEntry> {
         self.entries.get_mut(entry)
     }
+
+    /// Parses a block like [`PackBlock::from_reader`] does, except that entries
+    /// of an unknown type are replaced by empty entries instead of failing.
+    /// Only their link to the next block is kept. Returns the block together
+    /// with the indices of the replaced entries.
+    pub fn from_bytes_tolerant(buf: &[u8], encoding: Encoding) -> Pk2Result<(Self, Vec<usize>)> {
+        let mut block = PackBlock::default();
+        let mut replaced = Vec::new();
+        let entries = block
+            .entries
+            .iter_mut()
+            .zip(buf.chunks(PK2_FILE_ENTRY_SIZE));
+        for (idx, (entry, bytes)) in entries.enumerate() {
+            *entry = match PackEntry::from_reader(bytes, encoding) {
+                Ok(entry) => entry,
+                Err(Error::CorruptedFile) => {
+                    replaced.push(idx);
+                    let next_block_pos = PK2_FILE_ENTRY_SIZE - 10;
+                    let next_block = (&bytes[next_block_pos..]).read_u64::<LE>()?;
+                    PackEntry::new_empty(NonZeroU64::new(next_block))
+                }
+                Err(e) => return Err(e),
+            };
+        }
+        Ok((block, replaced))
+    }
 }
 
-impl RawIo for PackBlock {
-    fn from_reader<R: Read>(mut r: R) -> Pk2Result<Self> {
+impl PackBlock {
+    pub fn from_reader<R: Read>(mut r: R, encoding: Encoding) -> Pk2Result<Self> {
         let mut entries: [PackEntry; PK2_FILE_BLOCK_ENTRY_COUNT] = Default::default();
         for entry in &mut entries {
-            *entry = PackEntry::from_reader(&mut r)?;
+            *entry = PackEntry::from_reader(&mut r, encoding)?;
         }
         Ok(PackBlock { entries })
     }
 
-    fn to_writer<W: Write>(&self, mut w: W) -> IoResult<()> {
+    pub fn to_writer<W: Write>(&self, mut w: W, encoding: Encoding) -> IoResult<()> {
         self.entries
             .iter()
-            .map(|entry| entry.to_writer(&mut w))
+            .map(|entry| entry.to_writer(&mut w, encoding))
             .collect()
     }
 }
@@ -190,3 +330,25 @@ impl ops::IndexMut<usize> for PackBlock {
         &mut self.entries[idx]
     }
 }
+
+#[test]
+fn test_from_bytes_tolerant() {
+    let encoding = Encoding::default();
+    let mut block = PackBlock::default();
+    block[0] = PackEntry::new_directory(PK2_CURRENT_DIR_IDENT, PK2_ROOT_BLOCK, None);
+    block[19].set_next_block(BlockOffset(0x1234));
+    let mut buf = Vec::new();
+    block.to_writer(&mut buf, encoding).unwrap();
+    buf[5 * PK2_FILE_ENTRY_SIZE] = 0xFF;
+    buf[19 * PK2_FILE_ENTRY_SIZE] = 7;
+    assert!(matches!(
+        PackBlock::from_reader(&buf[..], encoding),
+        Err(Error::CorruptedFile)
+    ));
+    let (block, replaced) = PackBlock::from_bytes_tolerant(&buf, encoding).unwrap();
+    assert_eq!(replaced, [5, 19]);
+    assert!(block[0].is_dir());
+    assert!(block[5].is_empty());
+    assert!(block[19].is_empty());
+    assert_eq!(block[19].next_block().map(NonZeroU64::get), Some(0x1234));
+}
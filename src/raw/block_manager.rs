@@ -1,30 +1,181 @@
-use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
 use std::io;
 use std::path::{Component, Path};
 
 use super::block_chain::{PackBlock, PackBlockChain};
 use super::entry::{DirectoryEntry, PackEntry};
 use super::{BlockOffset, ChainIndex};
-use crate::constants::{PK2_ROOT_BLOCK, PK2_ROOT_BLOCK_VIRTUAL};
+use crate::constants::{
+    PK2_FILE_BLOCK_ENTRY_COUNT, PK2_FILE_BLOCK_SIZE, PK2_ROOT_BLOCK, PK2_ROOT_BLOCK_VIRTUAL,
+};
 use crate::error::{Error, Pk2Result};
-use crate::Blowfish;
+use crate::{Blowfish, Encoding};
+
+use elsa::sync::FrozenMap;
 
 /// Simple BlockManager backed by a hashmap.
 pub struct BlockManager {
     chains: HashMap<ChainIndex, PackBlockChain, NoHashHasherBuilder>,
+    // chains loaded on first access through a shared reference, only present when loading lazily
+    lazy: Option<FrozenMap<ChainIndex, Box<PackBlockChain>>>,
+    // damage worked around while loading, only present when loading tolerantly
+    repairs: Option<Vec<LoadRepair>>,
+}
+
+/// Damage of the index that has been worked around while loading an archive
+/// tolerantly. The affected chains are only changed in memory.
+#[derive(Debug)]
+pub enum LoadRepair {
+    /// The link from the block to the next block of the chain has been
+    /// removed, as it formed a cycle or pointed at a block that couldn't be
+    /// read.
+    UnlinkedBlock {
+        chain: ChainIndex,
+        block: BlockOffset,
+    },
+    /// An entry of an unknown type has been replaced by an empty entry.
+    ReplacedEntry { chain: ChainIndex, index: usize },
+    /// The first block of the chain couldn't be read, so the chain is missing.
+    UnreadableChain { chain: ChainIndex },
 }
 
 impl BlockManager {
     /// Parses the complete index of a pk2 file
-    pub fn new<F: io::Read + io::Seek>(bf: Option<&Blowfish>, mut file: F) -> Pk2Result<Self> {
-        let mut chains = HashMap::with_capacity_and_hasher(32, NoHashHasherBuilder);
+    pub fn new<F: io::Read + io::Seek>(
+        bf: Option<&Blowfish>,
+        encoding: Encoding,
+        file: F,
+    ) -> Pk2Result<Self> {
+        let mut this = BlockManager {
+            chains: HashMap::with_capacity_and_hasher(32, NoHashHasherBuilder),
+            lazy: None,
+            repairs: None,
+        };
+        this.load_all(bf, encoding, file)?;
+        this.insert_virtual_root();
+        Ok(this)
+    }
+
+    /// Parses the complete index of a pk2 file like [`BlockManager::new`],
+    /// except that damaged chains are worked around instead of failing. The
+    /// damage can be retrieved with [`BlockManager::take_repairs`].
+    pub fn new_tolerant<F: io::Read + io::Seek>(
+        bf: Option<&Blowfish>,
+        encoding: Encoding,
+        file: F,
+    ) -> Pk2Result<Self> {
+        let mut this = BlockManager {
+            chains: HashMap::with_capacity_and_hasher(32, NoHashHasherBuilder),
+            lazy: None,
+            repairs: Some(Vec::new()),
+        };
+        this.load_all(bf, encoding, file)?;
+        this.insert_virtual_root();
+        Ok(this)
+    }
+
+    /// Returns the damage that has been worked around while loading
+    /// tolerantly so far.
+    pub fn take_repairs(&mut self) -> Vec<LoadRepair> {
+        self.repairs
+            .as_mut()
+            .map(std::mem::take)
+            .unwrap_or_default()
+    }
+
+    /// Only parses the root chain of a pk2 file, every other chain has to be
+    /// loaded on first access with [`BlockManager::load`].
+    pub fn new_lazy<F: io::Read + io::Seek>(
+        bf: Option<&Blowfish>,
+        encoding: Encoding,
+        mut file: F,
+    ) -> Pk2Result<Self> {
+        let mut this = BlockManager {
+            chains: HashMap::with_capacity_and_hasher(32, NoHashHasherBuilder),
+            lazy: Some(FrozenMap::new()),
+            repairs: None,
+        };
+        let root = Self::read_chain_from_file_at(bf, encoding, &mut file, PK2_ROOT_BLOCK, None)?;
+        // the virtual root copies the times of the root chain, so the root
+        // chain has to be inserted first
+        this.chains.insert(PK2_ROOT_BLOCK, root);
+        this.insert_virtual_root();
+        Ok(this)
+    }
+
+    /// Whether there might be chains in the archive that haven't been loaded
+    /// yet.
+    #[inline]
+    pub fn is_lazy(&self) -> bool {
+        self.lazy.is_some()
+    }
+
+    /// Returns the chain at the given index, reading it from the file if it
+    /// hasn't been loaded yet.
+    pub fn load<F: io::Read + io::Seek>(
+        &self,
+        bf: Option<&Blowfish>,
+        encoding: Encoding,
+        mut file: F,
+        chain: ChainIndex,
+    ) -> Pk2Result<&PackBlockChain> {
+        if let Some(block_chain) = self.get(chain) {
+            return Ok(block_chain);
+        }
+        match &self.lazy {
+            Some(lazy) => {
+                let block_chain =
+                    Self::read_chain_from_file_at(bf, encoding, &mut file, chain, None)?;
+                Ok(lazy.insert(chain, Box::new(block_chain)))
+            }
+            None => Err(Error::InvalidChainIndex),
+        }
+    }
+
+    /// Loads every chain reachable from the root that hasn't been loaded yet.
+    /// Afterwards the manager is no longer lazy.
+    pub fn load_all<F: io::Read + io::Seek>(
+        &mut self,
+        bf: Option<&Blowfish>,
+        encoding: Encoding,
+        mut file: F,
+    ) -> Pk2Result<()> {
+        if let Some(mut lazy) = self.lazy.take() {
+            self.chains
+                .extend(lazy.as_mut().drain().map(|(idx, chain)| (idx, *chain)));
+        }
+        let mut visited = HashSet::with_hasher(NoHashHasherBuilder);
         let mut offsets = vec![PK2_ROOT_BLOCK];
         while let Some(offset) = offsets.pop() {
-            if chains.contains_key(&offset) {
+            if !visited.insert(offset) {
                 // skip offsets that are being pointed to multiple times
                 continue;
             }
-            let block_chain = Self::read_chain_from_file_at(bf, &mut file, offset)?;
+            let block_chain = match self.chains.entry(offset) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => {
+                    match Self::read_chain_from_file_at(
+                        bf,
+                        encoding,
+                        &mut file,
+                        offset,
+                        self.repairs.as_mut(),
+                    ) {
+                        Ok(block_chain) => entry.insert(block_chain),
+                        Err(e) if offset != PK2_ROOT_BLOCK && is_damage(&e) => {
+                            match &mut self.repairs {
+                                Some(repairs) => {
+                                    repairs.push(LoadRepair::UnreadableChain { chain: offset });
+                                    continue;
+                                }
+                                None => return Err(e),
+                            }
+                        }
+                        Err(e) => return Err(e),
+                    }
+                }
+            };
             // put all folder offsets of this chain into the stack to parse them next
             offsets.extend(
                 block_chain
@@ -33,11 +184,17 @@ impl BlockManager {
                     .filter(|d| d.is_normal_link())
                     .map(DirectoryEntry::children_position),
             );
-            chains.insert(offset, block_chain);
         }
-        let mut this = BlockManager { chains };
-        this.insert_virtual_root();
-        Ok(this)
+        Ok(())
+    }
+
+    /// Moves a lazily loaded chain into the mutable part of the manager.
+    fn promote(&mut self, chain: ChainIndex) {
+        if let Some(lazy) = &mut self.lazy {
+            if let Some(block_chain) = lazy.as_mut().remove(&chain) {
+                self.chains.insert(chain, *block_chain);
+            }
+        }
     }
 
     fn insert_virtual_root(&mut self) {
@@ -46,47 +203,162 @@ impl BlockManager {
             PK2_ROOT_BLOCK_VIRTUAL.into(),
             PackBlock::default(),
         )]);
-        virtual_root[0] = PackEntry::new_directory("/", PK2_ROOT_BLOCK, None);
+        let mut root = PackEntry::new_directory("/", PK2_ROOT_BLOCK, None);
+        // the times of the root are stored in its current directory link
+        let current_link = self.chains.get(&PK2_ROOT_BLOCK).map(|chain| &chain[0]);
+        if let (PackEntry::Directory(root), Some(PackEntry::Directory(current_link))) =
+            (&mut root, current_link)
+        {
+            root.access_time = current_link.access_time;
+            root.create_time = current_link.create_time;
+            root.modify_time = current_link.modify_time;
+        }
+        virtual_root.set_entry(0, root);
         self.chains.insert(virtual_root.chain_index(), virtual_root);
     }
 
     /// Reads a [`PackBlockChain`] from the given file at the specified offset.
-    /// Note: FIXME Can potentially end up in a neverending loop with a
-    /// specially crafted file.
+    /// Fails with [`Error::CorruptedFile`] if the blocks of the chain link to
+    /// each other in a cycle. If `repairs` is given, cycles, links to blocks
+    /// that can't be read and entries of unknown types are worked around and
+    /// recorded instead.
     fn read_chain_from_file_at<F: io::Read + io::Seek>(
         bf: Option<&Blowfish>,
+        encoding: Encoding,
         file: &mut F,
-        offset: ChainIndex,
+        chain: ChainIndex,
+        mut repairs: Option<&mut Vec<LoadRepair>>,
     ) -> Pk2Result<PackBlockChain> {
-        let mut blocks = Vec::new();
-        let mut offset = offset.into();
+        let mut blocks: Vec<(BlockOffset, PackBlock)> = Vec::new();
+        let mut visited = HashSet::with_hasher(NoHashHasherBuilder);
+        let mut offset = chain.into();
         loop {
-            let block = crate::io::read_block_at(bf, &mut *file, offset)?;
+            visited.insert(offset);
+            let block = match repairs.as_deref_mut() {
+                Some(repairs) => {
+                    match crate::io::read_block_at_tolerant(bf, encoding, &mut *file, offset) {
+                        Ok((block, replaced)) => {
+                            let base = blocks.len() * PK2_FILE_BLOCK_ENTRY_COUNT;
+                            repairs.extend(replaced.into_iter().map(|idx| {
+                                LoadRepair::ReplacedEntry {
+                                    chain,
+                                    index: base + idx,
+                                }
+                            }));
+                            block
+                        }
+                        Err(e) if is_damage(&e) && !blocks.is_empty() => {
+                            Self::unlink_last_block(&mut blocks, chain, repairs);
+                            break;
+                        }
+                        Err(e) => return Err(e),
+                    }
+                }
+                None => crate::io::read_block_at(bf, encoding, &mut *file, offset)?,
+            };
             let nc = block.entries().last().and_then(PackEntry::next_block);
             blocks.push((offset, block));
-            match nc {
-                Some(nc) => offset = BlockOffset(nc.get()),
-                None => break Ok(PackBlockChain::from_blocks(blocks)),
+            match nc.map(|nc| BlockOffset(nc.get())) {
+                Some(nc) if visited.contains(&nc) => match repairs.as_deref_mut() {
+                    Some(repairs) => {
+                        Self::unlink_last_block(&mut blocks, chain, repairs);
+                        break;
+                    }
+                    None => return Err(Error::CorruptedFile),
+                },
+                Some(nc) => offset = nc,
+                None => break,
+            }
+        }
+        Ok(PackBlockChain::from_blocks(blocks))
+    }
+
+    fn unlink_last_block(
+        blocks: &mut [(BlockOffset, PackBlock)],
+        chain: ChainIndex,
+        repairs: &mut Vec<LoadRepair>,
+    ) {
+        if let Some((offset, block)) = blocks.last_mut() {
+            if let Some(entry) = block.get_mut(PK2_FILE_BLOCK_ENTRY_COUNT - 1) {
+                entry.set_next_block(BlockOffset(0));
             }
+            repairs.push(LoadRepair::UnlinkedBlock {
+                chain,
+                block: *offset,
+            });
         }
     }
 
+    /// Returns the chain if it has been loaded already.
     #[inline]
     pub fn get(&self, chain: ChainIndex) -> Option<&PackBlockChain> {
-        self.chains.get(&chain)
+        match self.chains.get(&chain) {
+            Some(block_chain) => Some(block_chain),
+            None => self.lazy.as_ref()?.get(&chain),
+        }
     }
 
     #[inline]
     pub fn get_mut(&mut self, chain: ChainIndex) -> Option<&mut PackBlockChain> {
         assert_ne!(chain, PK2_ROOT_BLOCK_VIRTUAL);
+        self.promote(chain);
         self.chains.get_mut(&chain)
     }
 
     #[inline]
     pub fn insert(&mut self, chain: ChainIndex, block: PackBlockChain) {
+        if let Some(lazy) = &mut self.lazy {
+            lazy.as_mut().remove(&chain);
+        }
         self.chains.insert(chain, block);
     }
 
+    /// Removes the chain from this manager, returning it if it existed.
+    #[inline]
+    pub fn remove(&mut self, chain: ChainIndex) -> Option<PackBlockChain> {
+        assert_ne!(chain, PK2_ROOT_BLOCK_VIRTUAL);
+        self.promote(chain);
+        self.chains.remove(&chain)
+    }
+
+    /// Returns an iterator over all chains of the archive. When loading
+    /// lazily, only the chains that have been moved out of the lazy cache by
+    /// mutable access are returned, so [`BlockManager::load_all`] should be
+    /// called beforehand.
+    pub fn chains(&self) -> impl Iterator<Item = &PackBlockChain> {
+        self.chains
+            .values()
+            .filter(|chain| chain.chain_index() != PK2_ROOT_BLOCK_VIRTUAL)
+    }
+
+    /// Moves all chains and every file offset their entries refer to
+    /// according to `relocate`.
+    pub fn relocate(&mut self, relocate: impl Fn(u64) -> u64) {
+        assert!(!self.is_lazy());
+        let chains = std::mem::take(&mut self.chains);
+        for (chain_index, mut chain) in chains {
+            if chain_index != PK2_ROOT_BLOCK_VIRTUAL {
+                chain.relocate(&relocate);
+            }
+            self.chains.insert(chain.chain_index(), chain);
+        }
+    }
+
+    /// Returns an iterator over all (offset, length) regions of the archive
+    /// that are occupied by blocks or file data. The header is not included.
+    pub fn used_regions(&self) -> impl Iterator<Item = (u64, u64)> + '_ {
+        self.chains().flat_map(|chain| {
+            let blocks = chain
+                .block_offsets()
+                .map(|BlockOffset(offset)| (offset, PK2_FILE_BLOCK_SIZE as u64));
+            let data = chain
+                .entries()
+                .filter_map(PackEntry::as_file)
+                .map(|file| (file.pos_data(), file.size() as u64));
+            blocks.chain(data)
+        })
+    }
+
     pub fn resolve_path_to_parent<'path>(
         &self,
         current_chain: ChainIndex,
@@ -114,30 +386,9 @@ impl BlockManager {
     ) -> Pk2Result<(ChainIndex, usize, &PackEntry)> {
         self.resolve_path_to_parent(current_chain, path)
             .and_then(|(parent_index, name)| {
-                self.chains
-                    .get(&parent_index)
+                self.get(parent_index)
                     .ok_or(Error::InvalidChainIndex)?
-                    .entries()
-                    .enumerate()
-                    .find(|(_, entry)| entry.name_eq_ignore_ascii_case(name))
-                    .ok_or(Error::NotFound)
-                    .map(|(idx, entry)| (parent_index, idx, entry))
-            })
-    }
-
-    pub fn resolve_path_to_entry_and_parent_mut(
-        &mut self,
-        current_chain: ChainIndex,
-        path: &Path,
-    ) -> Pk2Result<(ChainIndex, usize, &mut PackEntry)> {
-        self.resolve_path_to_parent(current_chain, path)
-            .and_then(move |(parent_index, name)| {
-                self.chains
-                    .get_mut(&parent_index)
-                    .ok_or(Error::InvalidChainIndex)?
-                    .entries_mut()
-                    .enumerate()
-                    .find(|(_, entry)| entry.name_eq_ignore_ascii_case(name))
+                    .find_entry(name)
                     .ok_or(Error::NotFound)
                     .map(|(idx, entry)| (parent_index, idx, entry))
             })
@@ -155,8 +406,7 @@ impl BlockManager {
                 .as_os_str()
                 .to_str()
                 .ok_or(Error::NonUnicodePath)?;
-            self.chains
-                .get(&idx)
+            self.get(idx)
                 .ok_or(Error::InvalidChainIndex)?
                 .find_block_chain_index_of(comp)
         })
@@ -164,7 +414,8 @@ impl BlockManager {
 
     /// Traverses the path until it hits a non-existent component and returns
     /// the rest of the path as a peekable as well as the chain index of the
-    /// last valid part.
+    /// last valid part. Fails with [`Error::AlreadyExists`] if the whole path
+    /// exists and ends in a file.
     pub fn validate_dir_path_until<'p>(
         &self,
         mut chain: ChainIndex,
@@ -177,8 +428,7 @@ impl BlockManager {
                 .to_str()
                 .ok_or(Error::NonUnicodePath)?;
             match self
-                .chains
-                .get(&chain)
+                .get(chain)
                 .ok_or(Error::InvalidChainIndex)?
                 .find_block_chain_index_of(name)
             {
@@ -189,6 +439,10 @@ impl BlockManager {
                 }
                 // found a non-existent part, we are done here
                 Err(Error::NotFound) => break,
+                // the path ends in an existing file
+                Err(Error::ExpectedDirectory) if components.clone().nth(1).is_none() => {
+                    return Err(Error::AlreadyExists)
+                }
                 Err(e) => return Err(e),
             }
             let _ = components.next();
@@ -197,6 +451,16 @@ impl BlockManager {
     }
 }
 
+/// Whether the error is caused by the index pointing at data that doesn't
+/// exist, rather than by the underlying file.
+fn is_damage(e: &Error) -> bool {
+    match e {
+        Error::Io(e) => e.kind() == io::ErrorKind::UnexpectedEof,
+        Error::CorruptedFile => true,
+        _ => false,
+    }
+}
+
 #[derive(Default)]
 struct NoHashHasherBuilder;
 impl std::hash::BuildHasher for NoHashHasherBuilder {
@@ -224,3 +488,35 @@ impl std::hash::Hasher for NoHashHasher {
         self.0 = chain;
     }
 }
+
+#[test]
+fn test_new_tolerant() {
+    use std::io::Write;
+    let mut buf = io::Cursor::new(Vec::new());
+    let mut archive = crate::archive::Pk2::create_new_in(&mut buf, "").unwrap();
+    for path in &["/a/file", "/b/other"] {
+        archive
+            .create_file(path)
+            .unwrap()
+            .write_all(b"data")
+            .unwrap();
+    }
+    drop(archive);
+    let unreadable = 1u64 << 40;
+    crate::archive::patch_entry(buf.get_mut(), 1, "a", 106, &unreadable.to_le_bytes());
+    crate::archive::patch_entry(buf.get_mut(), 2, "other", 0, &[7]);
+
+    assert!(BlockManager::new(None, Encoding::default(), &mut buf).is_err());
+    let mut manager = BlockManager::new_tolerant(None, Encoding::default(), &mut buf).unwrap();
+    let repairs = manager.take_repairs();
+    assert_eq!(repairs.len(), 2);
+    assert!(repairs.iter().any(|repair| matches!(
+        repair,
+        LoadRepair::UnreadableChain { chain } if chain.0 == unreadable
+    )));
+    assert!(repairs
+        .iter()
+        .any(|repair| matches!(repair, LoadRepair::ReplacedEntry { .. })));
+    assert!(manager.take_repairs().is_empty());
+    assert!(manager.get(ChainIndex(unreadable)).is_none());
+}
@@ -5,10 +5,11 @@ use std::num::NonZeroU64;
 use std::time::SystemTime;
 
 use super::{BlockOffset, ChainIndex};
-use crate::constants::{PK2_CURRENT_DIR_IDENT, PK2_FILE_ENTRY_SIZE, PK2_PARENT_DIR_IDENT};
+use crate::constants::{
+    PK2_CURRENT_DIR_IDENT, PK2_FILE_ENTRY_SIZE, PK2_FILE_NAME_MAX_LEN, PK2_PARENT_DIR_IDENT,
+};
 use crate::error::{Error, Pk2Result};
-use crate::io::RawIo;
-use crate::FILETIME;
+use crate::{Encoding, FILETIME};
 
 #[derive(Clone, Eq, PartialEq)]
 pub struct EmptyEntry {
@@ -67,6 +68,11 @@ impl DirectoryEntry {
         self.pos_children
     }
 
+    #[inline]
+    pub(crate) fn set_children_position(&mut self, pos_children: ChainIndex) {
+        self.pos_children = pos_children;
+    }
+
     #[inline]
     pub fn next_block(&self) -> Option<NonZeroU64> {
         self.next_block
@@ -187,6 +193,20 @@ impl PackEntry {
         PackEntry::Empty(EmptyEntry::new(next_block))
     }
 
+    /// Checks whether `name` can be stored as the name of an entry with the
+    /// given encoding. Fails with [`Error::InvalidName`] if the name is empty,
+    /// contains a NUL or path separator, has characters that can't be encoded
+    /// or is longer than [`PK2_FILE_NAME_MAX_LEN`] bytes once encoded.
+    pub fn validate_name(name: &str, encoding: Encoding) -> Pk2Result<()> {
+        if name.is_empty() || name.contains(&['\0', '/', '\\'][..]) {
+            return Err(Error::InvalidName);
+        }
+        match encoding.try_encode(name) {
+            Some(encoded) if encoded.len() <= PK2_FILE_NAME_MAX_LEN => Ok(()),
+            _ => Err(Error::InvalidName),
+        }
+    }
+
     #[inline]
     pub fn as_directory(&self) -> Option<&DirectoryEntry> {
         match self {
@@ -252,6 +272,19 @@ impl PackEntry {
         }
     }
 
+    /// Changes the name of this entry. Names of entries inside a chain must
+    /// be changed through [`PackBlockChain::rename_entry`] instead to keep its
+    /// name index intact.
+    ///
+    /// [`PackBlockChain::rename_entry`]: super::block_chain::PackBlockChain::rename_entry
+    pub(super) fn set_name(&mut self, new_name: String) {
+        match self {
+            PackEntry::Empty(_) => (),
+            PackEntry::Directory(DirectoryEntry { name, .. })
+            | PackEntry::File(FileEntry { name, .. }) => *name = new_name,
+        }
+    }
+
     pub fn name_eq_ignore_ascii_case(&self, other: &str) -> bool {
         self.name()
             .map(|this| this.eq_ignore_ascii_case(other))
@@ -284,10 +317,10 @@ impl PackEntry {
 }
 
 use std::mem;
-impl RawIo for PackEntry {
+impl PackEntry {
     /// Reads an entry from the given Read instance always reading exactly
-    /// PK2_FILE_ENTRY_SIZE bytes.
-    fn from_reader<R: Read>(mut r: R) -> Pk2Result<Self> {
+    /// PK2_FILE_ENTRY_SIZE bytes, decoding its name with the given encoding.
+    pub fn from_reader<R: Read>(mut r: R, encoding: Encoding) -> Pk2Result<Self> {
         match r.read_u8()? {
             0 => {
                 r.read_exact(
@@ -308,13 +341,7 @@ impl RawIo for PackEntry {
                         .iter()
                         .position(|b| *b == 0)
                         .unwrap_or_else(|| buf.len());
-                    #[cfg(feature = "euc")]
-                    let name = encoding_rs::EUC_KR
-                        .decode_without_bom_handling(&buf[..end])
-                        .0;
-                    #[cfg(not(feature = "euc"))]
-                    let name = String::from_utf8_lossy(&buf[..end]);
-                    name.into_owned()
+                    encoding.decode(&buf[..end])
                 };
                 let access_time = FILETIME {
                     dwLowDateTime: r.read_u32::<LE>()?,
@@ -358,7 +385,8 @@ impl RawIo for PackEntry {
         }
     }
 
-    fn to_writer<W: Write>(&self, mut w: W) -> IoResult<()> {
+    /// Writes the entry, encoding its name with the given encoding.
+    pub fn to_writer<W: Write>(&self, mut w: W, encoding: Encoding) -> IoResult<()> {
         match self {
             PackEntry::Empty(EmptyEntry { next_block }) => {
                 w.write_all(
@@ -386,10 +414,7 @@ impl RawIo for PackEntry {
                 ..
             }) => {
                 w.write_u8(if self.is_dir() { 1 } else { 2 })?;
-                #[cfg(feature = "euc")]
-                let mut encoded = encoding_rs::EUC_KR.encode(name).0.into_owned();
-                #[cfg(not(feature = "euc"))]
-                let mut encoded = name.as_bytes().to_owned();
+                let mut encoded = encoding.encode(name).into_owned();
                 encoded.resize(81, 0);
                 w.write_all(&encoded)?;
                 w.write_u32::<LE>(access_time.dwLowDateTime)?;
@@ -0,0 +1,131 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Keeps track of the regions of an archive that are no longer referenced by
+/// any entry so that they can be handed out again instead of growing the
+/// file.
+#[derive(Default)]
+pub struct FreeList {
+    // offset -> length, regions never overlap or touch each other
+    regions: BTreeMap<u64, u64>,
+    // (length, offset) of every region, for looking up the best fit by size
+    by_size: BTreeSet<(u64, u64)>,
+}
+
+impl FreeList {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates a FreeList out of the gaps between the given used regions.
+    /// Everything between the end of the last used region and `file_len` is
+    /// considered free as well.
+    pub fn from_used_regions(used: impl IntoIterator<Item = (u64, u64)>, file_len: u64) -> Self {
+        let mut used: Vec<_> = used.into_iter().filter(|&(_, len)| len != 0).collect();
+        used.sort_unstable();
+        let mut this = Self::new();
+        let mut cursor = 0;
+        for (offset, len) in used {
+            if offset > cursor {
+                this.free(cursor, offset - cursor);
+            }
+            cursor = cursor.max(offset + len);
+        }
+        if file_len > cursor {
+            this.free(cursor, file_len - cursor);
+        }
+        this
+    }
+
+    /// Marks the region as free, merging it with adjacent free regions.
+    pub fn free(&mut self, mut offset: u64, mut len: u64) {
+        if len == 0 {
+            return;
+        }
+        if let Some((&prev_offset, &prev_len)) = self.regions.range(..=offset).next_back() {
+            if prev_offset + prev_len >= offset {
+                let end = (prev_offset + prev_len).max(offset + len);
+                self.remove(prev_offset, prev_len);
+                offset = prev_offset;
+                len = end - prev_offset;
+            }
+        }
+        while let Some((&next_offset, &next_len)) = self.regions.range(offset..).next() {
+            if next_offset > offset + len {
+                break;
+            }
+            self.remove(next_offset, next_len);
+            len = (next_offset + next_len).max(offset + len) - offset;
+        }
+        self.insert(offset, len);
+    }
+
+    /// Allocates a region of `len` bytes returning its offset, picking the
+    /// smallest free region that fits, the one with the lowest offset among
+    /// equally sized ones. Returns `None` if no region is big
+    /// enough in which case the caller has to append to the file instead.
+    pub fn allocate(&mut self, len: u64) -> Option<u64> {
+        if len == 0 {
+            return None;
+        }
+        let (region_len, offset) = *self.by_size.range((len, 0)..).next()?;
+        self.remove(offset, region_len);
+        if region_len > len {
+            self.insert(offset + len, region_len - len);
+        }
+        Some(offset)
+    }
+
+    fn insert(&mut self, offset: u64, len: u64) {
+        self.regions.insert(offset, len);
+        self.by_size.insert((len, offset));
+    }
+
+    fn remove(&mut self, offset: u64, len: u64) {
+        self.regions.remove(&offset);
+        self.by_size.remove(&(len, offset));
+    }
+
+    /// Returns the total amount of free bytes.
+    pub fn free_bytes(&self) -> u64 {
+        self.regions.values().sum()
+    }
+
+    /// An iterator over the free regions as (offset, length) pairs ordered by
+    /// offset.
+    pub fn regions(&self) -> impl Iterator<Item = (u64, u64)> + '_ {
+        self.regions.iter().map(|(&offset, &len)| (offset, len))
+    }
+}
+
+#[test]
+fn test_free_list_merge_and_allocate() {
+    let mut list = FreeList::from_used_regions(vec![(0, 10), (20, 10), (40, 5)], 50);
+    assert_eq!(
+        list.regions().collect::<Vec<_>>(),
+        [(10, 10), (30, 10), (45, 5)]
+    );
+    list.free(20, 10);
+    assert_eq!(list.regions().collect::<Vec<_>>(), [(10, 30), (45, 5)]);
+    assert_eq!(list.allocate(4), Some(45));
+    assert_eq!(list.allocate(25), Some(10));
+    assert_eq!(list.allocate(10), None);
+    assert_eq!(list.free_bytes(), 6);
+}
+
+#[test]
+fn test_free_list_best_fit() {
+    let mut list = FreeList::new();
+    list.free(100, 8);
+    list.free(0, 16);
+    list.free(50, 8);
+    list.free(200, 32);
+    assert_eq!(list.allocate(8), Some(50));
+    assert_eq!(list.allocate(8), Some(100));
+    assert_eq!(list.allocate(9), Some(0));
+    assert_eq!(list.allocate(20), Some(200));
+    assert_eq!(list.regions().collect::<Vec<_>>(), [(9, 7), (220, 12)]);
+    list.free(0, 9);
+    assert_eq!(list.allocate(16), Some(0));
+    assert_eq!(list.allocate(12), Some(220));
+    assert_eq!(list.free_bytes(), 0);
+}
@@ -43,15 +43,30 @@ impl PackHeader {
     /// Validate the signature of this header. Returns an error if the version
     /// or signature does not match.
     pub fn validate_sig(&self) -> Pk2Result<()> {
-        if &self.signature != PK2_SIGNATURE {
+        self.validate_sig_with(&[*PK2_SIGNATURE], &[PK2_VERSION])
+    }
+
+    /// Validate the signature of this header against the given accepted
+    /// signatures and versions. Returns an error if the version or signature
+    /// is not among them.
+    pub fn validate_sig_with(&self, signatures: &[[u8; 30]], versions: &[u32]) -> Pk2Result<()> {
+        if !signatures.contains(&self.signature) {
             Err(Error::CorruptedFile)
-        } else if self.version != PK2_VERSION {
+        } else if !versions.contains(&self.version) {
             Err(Error::UnsupportedVersion)
         } else {
             Ok(())
         }
     }
 
+    /// Verifies that this header belongs to an archive encrypted with the
+    /// given blowfish key, returning an error if it doesn't.
+    pub fn verify_key(&self, bf: &Blowfish) -> Pk2Result<()> {
+        let mut checksum = *PK2_CHECKSUM;
+        let _ = bf.encrypt(&mut checksum);
+        self.verify(checksum)
+    }
+
     /// Verifies the calculated checksum against this header returning an error
     /// if it doesn't match.
     pub fn verify(&self, checksum: [u8; 16]) -> Pk2Result<()> {